@@ -0,0 +1,41 @@
+// Render time of a 100,000-element chain built by prepending, so that it
+// leans right, against the same chain after `balance` has rebuilt it to
+// lean left, and against the same pieces put together by `DocBuilder`,
+// which makes an evenly balanced tree. Run with
+// `cargo bench --bench balance`.
+
+use std::hint::black_box;
+use std::time::{ Duration, Instant };
+
+use pretty_simple::doc::{ Doc, DocBuilder };
+
+const ELEMS : usize = 100_000;
+const RUNS : u32 = 10;
+
+fn per_render(doc : &Doc) -> Duration {
+    let start = Instant::now();
+    for _ in 0..RUNS {
+        black_box(doc.render(80));
+    }
+    start.elapsed() / RUNS
+}
+
+fn main() {
+    let right = (0..ELEMS).rev().fold(Doc::nil(), |acc, i| {
+        Doc::from(i).concat(",").concat(Doc::softline()).concat(acc)
+    });
+    let left = right.balance();
+    let mut builder = DocBuilder::new();
+    for i in 0..ELEMS {
+        builder.push(i);
+        builder.push(",");
+        builder.push(Doc::softline());
+    }
+    let even = builder.finish();
+    assert_eq!(right.render(80), left.render(80));
+    assert_eq!(right.render(80), even.render(80));
+    println!("{} elements", ELEMS);
+    println!("render, right-nested:              {:?}", per_render(&right));
+    println!("render, left-leaning (`balance`):  {:?}", per_render(&left));
+    println!("render, balanced (`DocBuilder`):   {:?}", per_render(&even));
+}
@@ -0,0 +1,55 @@
+// Allocations made building a space-separated list with `concat_space`,
+// against the same list built the way `concat_space` used to do it, with
+// a freshly allocated " " for every gap. Run with
+// `cargo bench --bench concat_space`.
+
+use std::alloc::{ GlobalAlloc, Layout, System };
+use std::sync::atomic::{ AtomicUsize, Ordering };
+
+use pretty_simple::doc::Doc;
+
+struct Counting;
+
+static ALLOCATIONS : AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout : Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr : *mut u8, layout : Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL : Counting = Counting;
+
+const WORDS : usize = 10_000;
+
+fn allocations<F : FnOnce() -> Doc>(build : F) -> usize {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    let doc = build();
+    let after = ALLOCATIONS.load(Ordering::Relaxed);
+    drop(doc);
+    after - before
+}
+
+fn main() {
+    // Warm up the shared leaves, which are allocated once per process.
+    drop(Doc::from("x").concat_space("y"));
+
+    let allocated = allocations(|| {
+        (0..WORDS).fold(Doc::static_text("word"), |acc, _| {
+            acc.concat(Doc::text(String::from(" "))).concat(Doc::static_text("word"))
+        })
+    });
+    let shared = allocations(|| {
+        (0..WORDS).fold(Doc::static_text("word"), |acc, _| acc.concat_space(Doc::static_text("word")))
+    });
+    println!("{} words, allocated \" \":  {} allocations ({:.2} per word)",
+             WORDS + 1, allocated, allocated as f64 / WORDS as f64);
+    println!("{} words, concat_space:     {} allocations ({:.2} per word)",
+             WORDS + 1, shared, shared as f64 / WORDS as f64);
+}
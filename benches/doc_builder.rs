@@ -0,0 +1,67 @@
+// Assembling a 100,000-element list with `DocBuilder`, against growing
+// it with `acc = acc.concat(next)` in a loop, and then rendering each.
+// The elements are made up front, so only putting them together is
+// timed (dropping the result included). Run with
+// `cargo bench --bench doc_builder`.
+
+use std::hint::black_box;
+use std::time::{ Duration, Instant };
+
+use pretty_simple::doc::{ Doc, DocBuilder, InnerDoc };
+
+const ELEMS : usize = 100_000;
+const RUNS : u32 = 10;
+
+fn element(i : usize) -> Doc {
+    if i == 0 {
+        Doc::from(i)
+    } else {
+        Doc::from(",").concat(Doc::line()).concat(Doc::from(i))
+    }
+}
+
+fn naive(elements : &[Doc]) -> Doc {
+    elements.iter().fold(Doc::nil(), |acc, elem| acc.concat(elem.clone()))
+}
+
+fn built(elements : &[Doc]) -> Doc {
+    let mut builder = DocBuilder::with_capacity(elements.len());
+    for elem in elements {
+        builder.push(elem.clone());
+    }
+    builder.finish()
+}
+
+fn depth(doc : &Doc) -> usize {
+    let mut deepest = 0;
+    let mut todo = vec![(doc, 0)];
+    while let Some((doc, depth)) = todo.pop() {
+        deepest = deepest.max(depth);
+        if let InnerDoc::Concat { lhs, rhs, .. } = doc.as_ref() {
+            todo.push((lhs, depth + 1));
+            todo.push((rhs, depth + 1));
+        }
+    }
+    deepest
+}
+
+fn per_run<T, F : FnMut() -> T>(mut f : F) -> Duration {
+    let start = Instant::now();
+    for _ in 0..RUNS {
+        black_box(f());
+    }
+    start.elapsed() / RUNS
+}
+
+fn main() {
+    let elements = (0..ELEMS).map(element).collect::<Vec<_>>();
+    let naive_doc = naive(&elements);
+    let built_doc = built(&elements);
+    assert_eq!(naive_doc.render(80), built_doc.render(80));
+    println!("{} elements; Concat depth {} for acc.concat(next), {} for DocBuilder",
+             ELEMS, depth(&naive_doc), depth(&built_doc));
+    println!("build,  acc.concat(next): {:?}", per_run(|| naive(&elements)));
+    println!("build,  DocBuilder:       {:?}", per_run(|| built(&elements)));
+    println!("render, acc.concat(next): {:?}", per_run(|| naive_doc.render(80)));
+    println!("render, DocBuilder:       {:?}", per_run(|| built_doc.render(80)));
+}
@@ -0,0 +1,32 @@
+// How long `fits_within` takes to answer for a document that's far too
+// wide to fit, against walking the whole of it the same way (which is
+// what it comes to with no budget) and against `fits_flat`, which reads
+// the width cached at the root. Run with `cargo bench --bench fits_within`.
+
+use std::hint::black_box;
+use std::time::{ Duration, Instant };
+
+use pretty_simple::doc::Doc;
+
+const WORDS : usize = 1_000_000;
+const RUNS : u32 = 20;
+
+fn per_call<F : FnMut() -> bool>(mut check : F) -> Duration {
+    let start = Instant::now();
+    for _ in 0..RUNS {
+        black_box(check());
+    }
+    start.elapsed() / RUNS
+}
+
+fn main() {
+    let doc = (0..WORDS).fold(Doc::static_text("word"), |acc, _| acc.concat(Doc::line()).concat(Doc::static_text("word")));
+    println!("{} words, {} columns flat", WORDS + 1, doc.flat_width());
+
+    let bounded = per_call(|| doc.fits_within(80));
+    let unbounded = per_call(|| doc.fits_within(usize::MAX));
+    let cached = per_call(|| doc.fits_flat(80));
+    println!("fits_within(80):         {:?}", bounded);
+    println!("fits_within(usize::MAX): {:?}", unbounded);
+    println!("fits_flat(80):           {:?}", cached);
+}
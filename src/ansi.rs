@@ -0,0 +1,338 @@
+use alloc::format;
+use alloc::string::{ String, ToString };
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+
+use crate::doc::AnnotatedDoc;
+
+// Terminal colors and text attributes, for use as the annotations of a
+// document rendered with `render_ansi`. They're written out as ANSI
+// Select Graphic Rendition escape sequences.
+
+// Back to the terminal's default look.
+const RESET : &str = "\x1b[0m";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Color {
+    Black,
+    Red,
+    Green,
+    Yellow,
+    Blue,
+    Magenta,
+    Cyan,
+    White,
+    // One of the 256 colors of the extended palette.
+    Fixed(u8),
+}
+
+impl Color {
+    // The SGR parameters for this color, as a foreground color if `base`
+    // is 30 and as a background color if it's 40.
+    fn sgr(self, base : u8) -> String {
+        let basic = |n : u8| (base + n).to_string();
+        match self {
+            Color::Black    => basic(0),
+            Color::Red      => basic(1),
+            Color::Green    => basic(2),
+            Color::Yellow   => basic(3),
+            Color::Blue     => basic(4),
+            Color::Magenta  => basic(5),
+            Color::Cyan     => basic(6),
+            Color::White    => basic(7),
+            Color::Fixed(n) => format!("{};5;{}", base + 8, n),
+        }
+    }
+}
+
+// How a part of the document should look. A style nested inside another
+// one only has to say what's different: the colors it leaves as `None`
+// and the attributes it leaves off are taken from the enclosing style.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Style {
+    pub fg : Option<Color>,
+    pub bg : Option<Color>,
+    pub bold : bool,
+    pub underline : bool,
+}
+
+impl Style {
+    pub fn new() -> Self {
+        Style::default()
+    }
+
+    // `inner`, with whatever it doesn't set taken from `self`.
+    fn within(self, inner : Style) -> Style {
+        Style {
+            fg : inner.fg.or(self.fg),
+            bg : inner.bg.or(self.bg),
+            bold : self.bold || inner.bold,
+            underline : self.underline || inner.underline,
+        }
+    }
+
+    // The escape sequence that switches to exactly this style, starting
+    // over from the terminal's default.
+    fn escape(self) -> String {
+        let mut params = Vec::new();
+        if self.bold {
+            params.push(String::from("1"));
+        }
+        if self.underline {
+            params.push(String::from("4"));
+        }
+        if let Some(fg) = self.fg {
+            params.push(fg.sgr(30));
+        }
+        if let Some(bg) = self.bg {
+            params.push(bg.sgr(40));
+        }
+        if params.is_empty() {
+            String::from(RESET)
+        } else {
+            format!("{}\x1b[{}m", RESET, params.join(";"))
+        }
+    }
+}
+
+impl AnnotatedDoc<Style> {
+    // Render with each styled part wrapped in the escape sequences that
+    // turn its style on and back off again. Nested styles combine, and
+    // leaving one goes back to whatever encloses it. The escapes don't
+    // count toward the width of the line. A style is never left on across
+    // a line break: it's turned off at the end of the line and turned back
+    // on after the next line's indentation, so the indentation itself is
+    // never colored.
+    pub fn render_ansi(&self, width : usize) -> String {
+        self.render_ansi_within(width, Style::default())
+    }
+
+    // `render_ansi` for output that goes inside text already styled as
+    // `outer`, e.g. a sub-document in a colored region. It starts with
+    // `outer` on, so the first character is already styled, styles in the
+    // document are combined with it, and leaving them goes back to it
+    // rather than to the default; it's still on at the end, for whatever
+    // follows. As with any style, it's off across line breaks and the
+    // indentation after them.
+    pub fn render_ansi_within(&self, width : usize, outer : Style) -> String {
+        let stack = RefCell::new(vec![outer]);
+        let push = |style : &Style| {
+            let mut stack = stack.borrow_mut();
+            let style = stack.last().map_or(*style, |outer| outer.within(*style));
+            stack.push(style);
+            style.escape()
+        };
+        let pop = |_ : &Style| {
+            let mut stack = stack.borrow_mut();
+            stack.pop();
+            stack.last().map_or(String::from(RESET), |outer| outer.escape())
+        };
+        let (body, indents) = self.render_annotated_indents(width, push, pop);
+        let initial = if outer == Style::default() { None } else { Some(outer.escape()) };
+        carry_across_lines(&body, &indents, initial)
+    }
+}
+
+// How `render_segments` combines the styles of nested annotations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stacking {
+    // The innermost annotation's style is used as it is.
+    Topmost,
+    // The styles are combined as `render_ansi` does: what the inner one
+    // doesn't set is taken from the ones around it.
+    Merged,
+}
+
+impl<A : Clone + 'static> AnnotatedDoc<A> {
+    // Render as a list of styled runs per line, for a terminal UI that
+    // draws styled text itself rather than writing escape sequences. The
+    // lines are what `render(width)` splits into at its newlines, each as
+    // the runs of text that make it up, with the style given by
+    // `style_of` for the annotations around them, combined as `stacking`
+    // says. Text outside every annotation, and the indentation at the
+    // start of each line, gets the default style. No run is empty, and
+    // neighboring runs always differ in style; an empty line has no runs.
+    pub fn render_segments<F>(&self, width : usize, stacking : Stacking, style_of : F) -> Vec<Vec<(String, Style)>>
+    where F : Fn(&A) -> Style {
+        self.render_segments_within(width, Style::default(), stacking, style_of)
+    }
+
+    // `render_segments` for output that goes inside text already styled
+    // as `outer`: text outside every annotation gets `outer`, and with
+    // `Stacking::Merged` the annotations' styles are combined with it. The
+    // indentation still gets the default style.
+    pub fn render_segments_within<F>(&self, width : usize, outer : Style, stacking : Stacking, style_of : F) -> Vec<Vec<(String, Style)>>
+    where F : Fn(&A) -> Style {
+        let (text, spans, indents) = self.render_spans_indents(width);
+        let mut indents = indents.into_iter().peekable();
+        let mut spans = spans.into_iter()
+                             .filter(|(range, _)| !range.is_empty())
+                             .map(|(range, annotation)| (range, style_of(&annotation)))
+                             .collect::<Vec<_>>();
+        // Entered in order, so of two that start at the same place the
+        // enclosing one comes first. The sort keeps it that way.
+        spans.sort_by_key(|(range, _)| range.start);
+
+        let mut lines = Vec::new();
+        // The annotations the current position is inside, innermost last,
+        // with where they end and the style that applies inside them.
+        let mut open : Vec<(usize, Style)> = Vec::new();
+        let mut spans = spans.into_iter().peekable();
+        let mut line_start = 0;
+        for line in text.split('\n') {
+            let line_end = line_start + line.len();
+            let indent_end = indents.next_if(|indent| indent.start == line_start).map_or(line_start, |indent| indent.end);
+            let mut runs : Vec<(String, Style)> = Vec::new();
+            let mut pos = line_start;
+            while pos < line_end {
+                while open.last().is_some_and(|(end, _)| *end <= pos) {
+                    open.pop();
+                }
+                while let Some((range, style)) = spans.next_if(|(range, _)| range.start <= pos) {
+                    if range.end <= pos {
+                        continue
+                    }
+                    let style = match (stacking, open.last()) {
+                        (Stacking::Merged, Some((_, enclosing))) => enclosing.within(style),
+                        (Stacking::Merged, None)                 => outer.within(style),
+                        (Stacking::Topmost, _)                   => style,
+                    };
+                    open.push((range.end, style));
+                }
+                // The run goes on until something opens or closes.
+                let mut next = line_end;
+                if let Some((end, _)) = open.last() {
+                    next = next.min(*end);
+                }
+                if let Some((range, _)) = spans.peek() {
+                    next = next.min(range.start);
+                }
+                if pos < indent_end {
+                    next = next.min(indent_end);
+                }
+                let style = if pos < indent_end {
+                    Style::default()
+                } else {
+                    open.last().map_or(outer, |(_, style)| *style)
+                };
+                let piece = &text[pos..next];
+                match runs.last_mut() {
+                    Some((run, last)) if *last == style => run.push_str(piece),
+                    _ => runs.push((piece.to_string(), style)),
+                }
+                pos = next;
+            }
+            lines.push(runs);
+            line_start = line_end + 1;
+        }
+        lines
+    }
+}
+
+// Close whatever style is active at the end of each line of `s`, and
+// reopen it after the next line's indentation (unless that line is empty).
+// `indents` is where the renderer wrote indentation, as byte ranges of
+// `s`; `initial` is the escape that's on from the start, which goes after
+// the first line's indentation even if there's nothing else on it.
+fn carry_across_lines(s : &str, indents : &[core::ops::Range<usize>], initial : Option<String>) -> String {
+    let mut out = String::with_capacity(s.len());
+    // The escape that most recently took effect, if it wasn't a reset.
+    let mut active = initial;
+    let mut indents = indents.iter().peekable();
+    let mut line_start = 0;
+    for (idx, line) in s.split('\n').enumerate() {
+        if idx > 0 {
+            if active.is_some() {
+                out.push_str(RESET);
+            }
+            out.push('\n');
+        }
+        let indent = indents.next_if(|indent| indent.start == line_start).map_or(0, |indent| indent.len());
+        match &active {
+            Some(escape) if indent < line.len() || idx == 0 => {
+                out.push_str(&line[..indent]);
+                out.push_str(escape);
+                out.push_str(&line[indent..]);
+            },
+            _ => out.push_str(line),
+        }
+        let mut rest = line;
+        while let Some(start) = rest.find("\x1b[") {
+            let Some(len) = rest[start..].find('m') else {
+                break
+            };
+            let escape = &rest[start..start + len + 1];
+            active = if escape == RESET { None } else { Some(escape.to_string()) };
+            rest = &rest[start + len + 1..];
+        }
+        line_start += line.len() + 1;
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::doc::Doc;
+
+    const BOLD : Style = Style { fg : None, bg : None, bold : true, underline : false };
+    const RED : Style = Style { fg : Some(Color::Red), bg : None, bold : false, underline : false };
+
+    fn run(text : &str, style : Style) -> (String, Style) {
+        (text.to_string(), style)
+    }
+
+    #[test]
+    fn nested_annotations_style_a_wrapped_line() {
+        let word = |s : &str| AnnotatedDoc::<Style>::text(s.to_string());
+        let inner = word("b").concat(AnnotatedDoc::newline()).concat("c").annotate(RED);
+        let doc = word("a").concat(AnnotatedDoc::newline()).concat(inner).concat(" d").annotate(BOLD)
+                  .concat(" e").nest(2).group();
+        assert_eq!(doc.render(80), "a b c d e");
+        assert_eq!(doc.render(6), "a\n  b\n  c d e");
+
+        let merged = doc.render_segments(6, Stacking::Merged, |style| *style);
+        assert_eq!(merged, vec![
+            vec![run("a", BOLD)],
+            vec![run("  ", Style::default()), run("b", BOLD.within(RED))],
+            vec![run("  ", Style::default()), run("c", BOLD.within(RED)), run(" d", BOLD), run(" e", Style::default())],
+        ]);
+        let topmost = doc.render_segments(6, Stacking::Topmost, |style| *style);
+        assert_eq!(topmost[2], vec![run("  ", Style::default()), run("c", RED), run(" d", BOLD), run(" e", Style::default())]);
+    }
+
+    #[test]
+    fn initial_style_applies_from_the_first_character() {
+        let doc = AnnotatedDoc::<Style>::text(String::from("plain ")).concat(AnnotatedDoc::text(String::from("red")).annotate(RED));
+        let bold = BOLD.escape();
+        assert_eq!(doc.render_ansi_within(80, BOLD), format!("{}plain {}red{}", bold, BOLD.within(RED).escape(), bold));
+        assert_eq!(doc.render_ansi_within(80, Style::default()), doc.render_ansi(80));
+        assert_eq!(doc.render_segments_within(80, BOLD, Stacking::Merged, |style| *style),
+                   vec![vec![run("plain ", BOLD), run("red", BOLD.within(RED))]]);
+    }
+
+    #[test]
+    fn style_is_reapplied_after_the_indentation_of_a_split_span() {
+        // The second line is indented by 2, and its text starts with two
+        // spaces of its own, which are part of the red span.
+        let span = AnnotatedDoc::<Style>::text(String::from("a")).concat(AnnotatedDoc::hardline()).concat("  b").annotate(RED);
+        let doc = AnnotatedDoc::text(String::from("x")).concat(AnnotatedDoc::hardline()).concat(span).nest(2);
+        assert_eq!(doc.render_ansi(80), format!("x\n  {}a{}\n  \x1b[31m  b{}", RED.escape(), RESET, RESET));
+        assert_eq!(doc.render_segments(80, Stacking::Merged, |style| *style), vec![
+            vec![run("x", Style::default())],
+            vec![run("  ", Style::default()), run("a", RED)],
+            vec![run("  ", Style::default()), run("  b", RED)],
+        ]);
+    }
+
+    #[test]
+    fn segments_of_unannotated_text() {
+        let doc = Doc::from("x").concat(Doc::hardline()).concat(Doc::hardline()).concat("y");
+        assert_eq!(doc.render_segments(80, Stacking::Merged, |_| BOLD), vec![
+            vec![run("x", Style::default())],
+            vec![],
+            vec![run("y", Style::default())],
+        ]);
+    }
+}
@@ -1,8 +1,64 @@
 #![allow(unused_parens)]
-use std::sync::Arc;
+use alloc::borrow::Cow;
+use alloc::format;
+use alloc::rc::Rc;
+use alloc::string::{ String, ToString };
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::convert::TryFrom;
 
+use unicode_width::{ UnicodeWidthChar, UnicodeWidthStr };
 use InnerDoc::*;
 use crate::parenable::Parenable;
+use crate::placeholder::{ PartialRender, PlaceholderId };
+
+#[cfg(feature = "serde")]
+mod serialize;
+
+// Without `std` there's no `HashMap`, so the maps (and sets) keyed by
+// group names, tab names and fold ids are ordered ones there instead.
+#[cfg(feature = "std")]
+pub type Map<K, V> = std::collections::HashMap<K, V>;
+#[cfg(feature = "std")]
+pub type Set<T> = std::collections::HashSet<T>;
+#[cfg(not(feature = "std"))]
+pub type Map<K, V> = alloc::collections::BTreeMap<K, V>;
+#[cfg(not(feature = "std"))]
+pub type Set<T> = alloc::collections::BTreeSet<T>;
+
+
+// The leaves that get made over and over (spaces, line breaks, brackets)
+// are built once and shared between `Doc`s, so making one only bumps a
+// refcount. Whether `Self` is a `Doc` is found out by its type, which is
+// why annotations have to be `'static`; with any other annotation type
+// it's a new node each time. Without `std` there's no `OnceLock` to keep
+// them in, so there it's always a new node.
+#[cfg(feature = "std")]
+macro_rules! shared_leaf {
+    (text $s:literal) => {
+        shared_leaf!(Text { s : Cow::Borrowed($s), len : display_width($s) })
+    };
+    ($leaf:expr) => {{
+        static SHARED : std::sync::OnceLock<Doc> = std::sync::OnceLock::new();
+        let shared : &dyn core::any::Any = SHARED.get_or_init(|| Doc::from($leaf));
+        match shared.downcast_ref::<Self>() {
+            Some(doc) => doc.clone(),
+            None      => Self::from($leaf),
+        }
+    }};
+}
+
+#[cfg(not(feature = "std"))]
+macro_rules! shared_leaf {
+    (text $s:literal) => {
+        shared_leaf!(Text { s : Cow::Borrowed($s), len : display_width($s) })
+    };
+    ($leaf:expr) => {
+        Self::from($leaf)
+    };
+}
 
 
 /*
@@ -15,6 +71,12 @@ element's constructor.
 #[derive(Debug, Clone, Copy)]
 pub struct RenderInfo {
     flatmode : bool,
+    // Set inside a `group_first_line` whose first line fit: each newline
+    // is then a space if what follows it (up to the next one) still fits,
+    // and a real newline otherwise.
+    fillmode : bool,
+    // Set inside `Doc::continued`: broken newlines get a ` \` first.
+    continued : bool,
     nest : usize,
     dist_next_newline : usize,
     line_width : usize,
@@ -24,6 +86,8 @@ impl RenderInfo {
     pub fn new(flatmode : bool, nest : usize, dist_next_newline : usize, line_width : usize) -> Self {
         RenderInfo {
             flatmode,
+            fillmode : false,
+            continued : false,
             nest,
             dist_next_newline,
             line_width
@@ -32,6 +96,369 @@ impl RenderInfo {
 }
 
 
+// How a named group (see `Doc::group_named`) should be laid out,
+// overriding the usual fit check. Handy for chasing a layout bug
+// without touching the code that builds the document.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GroupMode {
+    ForceBreak,
+    ForceFlat,
+    Auto,
+}
+
+// See `RenderOptions::indent_fn`.
+pub type IndentFn = Rc<RefCell<dyn FnMut(usize, usize) -> String>>;
+
+// Knobs for `Doc::render_with`. `RenderOptions::new(w)` gives exactly the
+// behavior of `render(w)`.
+#[derive(Clone)]
+pub struct RenderOptions {
+    pub line_width : usize,
+    // Forced layouts for groups built with `group_named`, by name. A
+    // forced group ignores the fit check entirely, even inside a parent
+    // that's rendering flat. Groups without an entry (or with `Auto`) are
+    // laid out as usual.
+    pub group_overrides : Map<&'static str, GroupMode>,
+    // Give up after this many nodes, as a guard against documents built
+    // from untrusted input. Only `try_render_with` reports running out;
+    // the other render methods panic.
+    pub max_nodes : Option<usize>,
+    // Whether a group only goes flat if the content after it, up to the
+    // next possible break, also fits on the line (`true`, the default),
+    // or as soon as the group itself fits (`false`).
+    //
+    // With `true` a group breaks to make room for what follows it, which
+    // keeps an unbreakable tail (a closing `);`, a trailing comment) from
+    // overflowing, but it also means a group gets broken pointlessly when
+    // the next break is inside a later group that could take the break
+    // instead. With `false` the later group takes the break and the
+    // earlier one stays inline, at the risk of overflowing when the text
+    // right after the group can't be broken at all.
+    pub trailing_content_forces_break : bool,
+    // Produces the start of every line in place of the usual indentation:
+    // it's called with the line's nesting level and (zero-based) line
+    // number, once per line as the line is started -- the first line
+    // included -- and whatever it returns is written as that line's
+    // leading text. Its width is what the layout counts, so e.g. a gutter
+    // of line numbers pushes content over. Following the usual rule for
+    // indentation, a line that ends up empty is left empty, even though
+    // the function was still called for it. Set with `set_indent_fn`.
+    pub indent_fn : Option<IndentFn>,
+    // The most non-indentation text a line may have, on top of the limit
+    // `line_width` sets on the line as a whole: a group only goes flat if
+    // it fits in both. With this deeply nested content doesn't get packed
+    // onto long lines just because there happens to be room to the right
+    // of the indentation. `None` (the default) means it's worked out from
+    // `ribbon_fraction`.
+    pub ribbon : Option<usize>,
+    // The ribbon as a fraction of the line width, as in Haskell's
+    // Text.PrettyPrint, for when `ribbon` isn't set. It's clamped to
+    // `[0.0, 1.0]`, and the ribbon it gives to at least one column. The
+    // default, `1.0`, makes the ribbon the whole line, i.e. no extra limit.
+    pub ribbon_fraction : f32,
+    // Folds (see `Doc::foldable`) to render collapsed, showing just their
+    // summary.
+    pub collapsed : Set<FoldId>,
+    // What to do with a `Doc::right_align_suffix` that doesn't fit on its
+    // line: write it anyway, one space after the line's content (`false`,
+    // the default), or leave it out (`true`).
+    pub drop_overflowing_right_suffix : bool,
+    // What one unit of `Doc::nest` stands for. `Spaces(1)`, the default,
+    // is a column of spaces per unit, so `nest(4)` indents by four spaces.
+    pub indent : Indent,
+    // How many columns a tab takes up, for `Indent::Tabs`.
+    pub tab_width : usize,
+    // What goes at the end of each line. `Lf` by default.
+    pub line_ending : LineEnding,
+    // What to do about line breaks at the very end of the output.
+    // `Verbatim` by default.
+    pub trailing_newline : TrailingNewline,
+    // Write any token wider than this cut short, with "…" at the end to
+    // show it was, and the whole thing no wider than the limit. A token is
+    // a word of a text atom, i.e. a run of it between spaces, so a text
+    // with spaces in it is cut word by word. It's purely a matter of
+    // what's written: the layout is decided from the atoms' real widths,
+    // as if they'd been written out in full. `None`, the default, writes
+    // everything as it is.
+    pub max_token_width : Option<usize>,
+    // How the content of a `fill` (or `fill_sep`) that doesn't fit on one
+    // line is broken into lines. `Greedy` by default.
+    pub line_break : LineBreak,
+    // Cut every run of blank lines the layout produces (from breaks with
+    // nothing between them) down to at most this many. The blank line of
+    // a `Doc::user_blank_line` is always written, and counts toward the
+    // run, so ordinary breaks right after it are the ones that go. `None`,
+    // the default, writes every break.
+    pub max_consecutive_blank_lines : Option<usize>,
+}
+
+// See `RenderOptions::line_break`. Either way only a `fill` is affected;
+// the rest of the layout is decided group by group as usual.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineBreak {
+    // Put as many elements on each line as fit, one line at a time.
+    Greedy,
+    // Choose all the breaks at once, Knuth-Plass style, to keep the lines
+    // about equally long: the sum of the squares of the room left at the
+    // end of each line but the last is made as small as it can be. A fill
+    // with an element that has a line break of its own in it is laid out
+    // greedily anyway.
+    Optimal,
+}
+
+// The line break `RenderOptions::line_ending` writes. Either way it only
+// ends the line; neither character counts toward the line's width.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineEnding {
+    Lf,
+    CrLf,
+}
+
+impl LineEnding {
+    fn as_str(self) -> &'static str {
+        match self {
+            LineEnding::Lf   => "\n",
+            LineEnding::CrLf => "\r\n",
+        }
+    }
+}
+
+// What `RenderOptions::trailing_newline` does with the line breaks the
+// document ends in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrailingNewline {
+    // Write them as the document says, however many there are.
+    Verbatim,
+    // End in exactly one, adding it or dropping the extras as needed, as
+    // POSIX tools expect of a text file. Output with nothing but line
+    // breaks in it comes out empty.
+    Single,
+    // End in none.
+    Strip,
+}
+
+// The unit `RenderOptions::indent` measures nesting in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Indent {
+    // This many spaces per unit.
+    Spaces(usize),
+    // A tab per unit, `tab_width` columns wide as far as layout is
+    // concerned. Indentation that isn't a whole number of tabs (from
+    // `align`, say) is made up with spaces after the tabs.
+    Tabs,
+}
+
+impl RenderOptions {
+    pub fn new(line_width : usize) -> Self {
+        RenderOptions {
+            line_width,
+            group_overrides : Map::new(),
+            max_nodes : None,
+            trailing_content_forces_break : true,
+            indent_fn : None,
+            ribbon : None,
+            ribbon_fraction : 1.0,
+            collapsed : Set::new(),
+            drop_overflowing_right_suffix : false,
+            indent : Indent::Spaces(1),
+            tab_width : 8,
+            line_ending : LineEnding::Lf,
+            trailing_newline : TrailingNewline::Verbatim,
+            max_token_width : None,
+            line_break : LineBreak::Greedy,
+            max_consecutive_blank_lines : None,
+        }
+    }
+
+    // The columns a unit of nesting takes up.
+    fn indent_width(&self) -> usize {
+        match self.indent {
+            Indent::Spaces(n) => n,
+            Indent::Tabs      => self.tab_width,
+        }
+    }
+
+    // The ribbon in effect for a line `line_width` columns wide.
+    fn ribbon_width(&self, line_width : usize) -> usize {
+        match self.ribbon {
+            Some(ribbon) => ribbon,
+            None => ((line_width as f32 * self.ribbon_fraction.clamp(0.0, 1.0)) as usize).max(1),
+        }
+    }
+
+    pub fn set_indent_fn<F>(&mut self, f : F)
+    where F : FnMut(usize, usize) -> String + 'static {
+        self.indent_fn = Some(Rc::new(RefCell::new(f)));
+    }
+}
+
+impl core::fmt::Debug for RenderOptions {
+    fn fmt(&self, f : &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_struct("RenderOptions")
+         .field("line_width", &self.line_width)
+         .field("group_overrides", &self.group_overrides)
+         .field("max_nodes", &self.max_nodes)
+         .field("trailing_content_forces_break", &self.trailing_content_forces_break)
+         .field("indent_fn", &self.indent_fn.as_ref().map(|_| "<fn>"))
+         .field("ribbon", &self.ribbon)
+         .field("ribbon_fraction", &self.ribbon_fraction)
+         .field("collapsed", &self.collapsed)
+         .field("drop_overflowing_right_suffix", &self.drop_overflowing_right_suffix)
+         .field("indent", &self.indent)
+         .field("tab_width", &self.tab_width)
+         .field("line_ending", &self.line_ending)
+         .field("trailing_newline", &self.trailing_newline)
+         .field("max_token_width", &self.max_token_width)
+         .field("line_break", &self.line_break)
+         .field("max_consecutive_blank_lines", &self.max_consecutive_blank_lines)
+         .finish()
+    }
+}
+
+// Names a region made with `Doc::foldable`, so it can be collapsed with
+// `RenderOptions::collapsed`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct FoldId(pub usize);
+
+// One group's layout, as recorded by `Doc::render_trace`: its name (if
+// it was made with `group_named`), the column it started at, its flat
+// width, how many columns were left on the line there, and whether it
+// ended up broken. A group inside one that's flat is always flat, but it
+// still gets an entry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GroupDecision {
+    pub name : Option<&'static str>,
+    pub column : usize,
+    pub flat_width : usize,
+    pub available : usize,
+    pub broke : bool,
+}
+
+// Where a `Doc::foldable` region ended up in the output of
+// `Doc::render_detailed`: a byte range into the text, the (zero-based,
+// half-open) range of lines it's on, and whether it was collapsed to its
+// summary. A viewer can toggle a fold by flipping its `id` in
+// `RenderOptions::collapsed` and rendering again.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FoldMarker {
+    pub id : FoldId,
+    pub range : core::ops::Range<usize>,
+    pub lines : core::ops::Range<usize>,
+    pub collapsed : bool,
+}
+
+// A line that came out wider than `Doc::render_checked` was asked for:
+// its (zero-based) line number and its width in display columns.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Overflow {
+    pub line : usize,
+    pub width : usize,
+}
+
+// `try_render_with` stopped because it had processed `max_nodes`
+// nodes and the document still wasn't done.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RenderLimit {
+    pub max_nodes : usize,
+}
+
+impl core::fmt::Display for RenderLimit {
+    fn fmt(&self, f : &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "document has more than {} nodes to render", self.max_nodes)
+    }
+}
+
+impl core::error::Error for RenderLimit {}
+
+// How `Doc::string_literal_with` quotes and escapes a string. Backslashes,
+// the quote character itself, and control characters are always escaped:
+// `\n`, `\r`, `\t` and `\0` for the usual ones, and `\u{..}` for the rest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuoteStyle {
+    // What goes at both ends. `"` by default.
+    pub quote : char,
+    // Whether everything outside ASCII is written as a `\u{..}` escape
+    // too, for output that has to be plain ASCII. Off by default.
+    pub escape_non_ascii : bool,
+}
+
+impl Default for QuoteStyle {
+    fn default() -> Self {
+        QuoteStyle {
+            quote : '"',
+            escape_non_ascii : false,
+        }
+    }
+}
+
+// Output encodings for `render_bytes`/`render_to_writer`. The `Utf8Bom`
+// variant and both UTF-16 variants start the output with a byte order
+// mark; the plain `Utf8` variant is exactly the bytes of `render`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Utf8,
+    Utf8Bom,
+    Utf16Le,
+    Utf16Be,
+}
+
+impl Encoding {
+    // Transcode already-rendered text, prepending the BOM if there is one.
+    pub fn encode(self, s : &str) -> Vec<u8> {
+        let mut out = self.bom().to_vec();
+        out.extend(self.encode_piece(s).iter());
+        out
+    }
+
+    fn bom(self) -> &'static [u8] {
+        match self {
+            Encoding::Utf8    => &[],
+            Encoding::Utf8Bom => &[0xEF, 0xBB, 0xBF],
+            Encoding::Utf16Le => &[0xFF, 0xFE],
+            Encoding::Utf16Be => &[0xFE, 0xFF],
+        }
+    }
+
+    // Transcode some text, without a BOM.
+    fn encode_piece(self, s : &str) -> Cow<'_, [u8]> {
+        match self {
+            Encoding::Utf8 | Encoding::Utf8Bom => Cow::Borrowed(s.as_bytes()),
+            Encoding::Utf16Le => Cow::Owned(s.encode_utf16().flat_map(u16::to_le_bytes).collect()),
+            Encoding::Utf16Be => Cow::Owned(s.encode_utf16().flat_map(u16::to_be_bytes).collect()),
+        }
+    }
+}
+
+// Adapts an `io::Write` to the `fmt::Write` that `render_to` writes to,
+// encoding the text on the way through. `fmt::Error` can't carry the
+// underlying error, so it's kept here.
+#[cfg(feature = "std")]
+struct IoSink<'a, W> {
+    w : &'a mut W,
+    encoding : Encoding,
+    error : Option<std::io::Error>,
+}
+
+#[cfg(feature = "std")]
+impl<W : std::io::Write> core::fmt::Write for IoSink<'_, W> {
+    fn write_str(&mut self, s : &str) -> core::fmt::Result {
+        self.w.write_all(&self.encoding.encode_piece(s)).map_err(|e| {
+            self.error = Some(e);
+            core::fmt::Error
+        })
+    }
+}
+
+// A sink for renders that only want the measurements `Output` takes
+// along the way, not the text.
+struct Discard;
+
+impl core::fmt::Write for Discard {
+    fn write_str(&mut self, _ : &str) -> core::fmt::Result {
+        Ok(())
+    }
+}
+
 /*
 Fundamentally, the leaf constructors `Text` and `Newline`
 form the actual text of what you want to render. Everything else
@@ -61,54 +488,480 @@ The tree you end up with is left-spined
 // all of the methods defined on `InnerDoc` can be accessed via a `Doc`.
 // The only difference you're likely to experience is that when pattern 
 // matching, you'll need to use `match d.as_ref()` instead of `match d`.
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub struct Doc(Arc<InnerDoc>);
+//
+// A document can carry annotations of type `A` (see `annotate`), which
+// the layout ignores. Most documents don't have any, and `Doc` is the name
+// for those. The annotation type has to be `'static`, so that a `Doc` can
+// be told apart from the rest and share its common leaves (see
+// `shared_leaf`).
+#[derive(Debug)]
+pub struct AnnotatedDoc<A : 'static>(Arc<InnerDoc<A>>);
+
+pub type Doc = AnnotatedDoc<()>;
+
+// Dropping the last reference to a long `Concat` chain would otherwise
+// recurse once per node on the way down the spine. Instead, a node that's
+// about to go away is emptied out (swapped for `Nil`, which has nothing
+// to drop) and its children are dropped from a work-list, so the
+// recursion never goes more than one level deep.
+impl<A : 'static> Drop for AnnotatedDoc<A> {
+    fn drop(&mut self) {
+        let Some(inner) = Arc::get_mut(&mut self.0) else {
+            return
+        };
+        let mut todo = Vec::new();
+        core::mem::replace(inner, Nil).take_children(&mut todo);
+        while let Some(mut doc) = todo.pop() {
+            if let Some(inner) = Arc::get_mut(&mut doc.0) {
+                core::mem::replace(inner, Nil).take_children(&mut todo);
+            }
+        }
+    }
+}
+
+impl<A> InnerDoc<A> {
+    // The documents this node holds, in the order `take_children` gives
+    // them.
+    fn children(&self) -> Vec<&AnnotatedDoc<A>> {
+        match self {
+            Concat { lhs, rhs, .. } => vec![lhs, rhs],
+            Fold { summary, body, .. } => vec![summary, body],
+            Nest { doc, .. }
+            | Group { doc, .. }
+            | Placeholder { doc, .. }
+            | WithWidth { doc, .. }
+            | KeepTogether { doc, .. }
+            | Continued { doc, .. }
+            | LineSuffix { doc }
+            | RightSuffix { content : doc }
+            | Align { doc, .. }
+            | Fill { doc, .. }
+            | Padded { doc, .. }
+            | Annotated { doc, .. }
+            | LineCount { body : doc, .. } => vec![doc],
+            Alt { options, .. } => options.iter().collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    // `children`, to be replaced. Only `DocInterner` needs these.
+    #[cfg(feature = "std")]
+    fn children_mut(&mut self) -> Vec<&mut AnnotatedDoc<A>> {
+        match self {
+            Concat { lhs, rhs, .. } => vec![lhs, rhs],
+            Fold { summary, body, .. } => vec![summary, body],
+            Nest { doc, .. }
+            | Group { doc, .. }
+            | Placeholder { doc, .. }
+            | WithWidth { doc, .. }
+            | KeepTogether { doc, .. }
+            | Continued { doc, .. }
+            | LineSuffix { doc }
+            | RightSuffix { content : doc }
+            | Align { doc, .. }
+            | Fill { doc, .. }
+            | Padded { doc, .. }
+            | Annotated { doc, .. }
+            | LineCount { body : doc, .. } => vec![doc],
+            Alt { options, .. } => options.iter_mut().collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    // Move the documents this node holds into `acc`.
+    fn take_children(self, acc : &mut Vec<AnnotatedDoc<A>>) {
+        match self {
+            Concat { lhs, rhs, .. } => {
+                acc.push(lhs);
+                acc.push(rhs);
+            },
+            Fold { summary, body, .. } => {
+                acc.push(summary);
+                acc.push(body);
+            },
+            Nest { doc, .. }
+            | Group { doc, .. }
+            | Placeholder { doc, .. }
+            | WithWidth { doc, .. }
+            | KeepTogether { doc, .. }
+            | Continued { doc, .. }
+            | LineSuffix { doc }
+            | RightSuffix { content : doc }
+            | Align { doc, .. }
+            | Fill { doc, .. }
+            | Padded { doc, .. }
+            | Annotated { doc, .. }
+            | LineCount { body : doc, .. } => acc.push(doc),
+            Alt { options, .. } => acc.extend(options),
+            Nil
+            | Newline
+            | NewlineZero
+            | Text { .. }
+            | TabStop { .. }
+            | SetTab { .. }
+            | TabTo { .. }
+            | UserBlankLine
+            | Hardline
+            | Column { .. }
+            | Nesting { .. } => (),
+        }
+    }
+}
+
+// Documents are equal when they have the same structure; see
+// `structurally_eq`.
+impl<A : PartialEq + 'static> PartialEq for AnnotatedDoc<A> {
+    fn eq(&self, other : &Self) -> bool {
+        self.structurally_eq(other)
+    }
+}
+
+impl<A : Eq + 'static> Eq for AnnotatedDoc<A> {}
+
+// Hashes the same things `structurally_eq` compares, so documents that
+// are equal hash the same even if they don't share any nodes, and the
+// cached measurements are left out.
+impl<A : core::hash::Hash + 'static> core::hash::Hash for AnnotatedDoc<A> {
+    fn hash<H : core::hash::Hasher>(&self, state : &mut H) {
+        self.as_ref().hash(state)
+    }
+}
+
+impl<A : core::hash::Hash> core::hash::Hash for InnerDoc<A> {
+    fn hash<H : core::hash::Hasher>(&self, state : &mut H) {
+        let mut todo = vec![self];
+        while let Some(node) = todo.pop() {
+            node.hash_own(state, &mut todo);
+        }
+    }
+}
+
+impl<A : core::hash::Hash> InnerDoc<A> {
+    // Hash what's in this node itself, and push its children onto `todo`,
+    // last first, for the caller to deal with.
+    fn hash_own<'d, H : core::hash::Hasher>(&'d self, state : &mut H, todo : &mut Vec<&'d InnerDoc<A>>) {
+        use core::hash::Hash;
+        core::mem::discriminant(self).hash(state);
+        match self {
+            Nil
+            | Newline
+            | NewlineZero
+            | UserBlankLine
+            | Hardline => (),
+            Text { s, .. } => s.hash(state),
+            Concat { lhs, rhs, .. } => {
+                todo.push(rhs.as_ref());
+                todo.push(lhs.as_ref());
+            },
+            Nest { nest, doc, .. } => {
+                nest.hash(state);
+                todo.push(doc.as_ref());
+            },
+            WithWidth { width : n, doc, .. }
+            | Align { extra : n, doc, .. } => {
+                n.hash(state);
+                todo.push(doc.as_ref());
+            },
+            Padded { width, or_break, doc, .. } => {
+                width.hash(state);
+                or_break.hash(state);
+                todo.push(doc.as_ref());
+            },
+            Group { doc, name, first_line, .. } => {
+                name.hash(state);
+                first_line.hash(state);
+                todo.push(doc.as_ref());
+            },
+            Placeholder { id, doc, .. } => {
+                id.hash(state);
+                todo.push(doc.as_ref());
+            },
+            KeepTogether { doc, .. }
+            | Continued { doc, .. }
+            | Fill { doc, .. }
+            | RightSuffix { content : doc }
+            | LineSuffix { doc } => todo.push(doc.as_ref()),
+            Fold { id, summary, body, .. } => {
+                id.hash(state);
+                todo.push(body.as_ref());
+                todo.push(summary.as_ref());
+            },
+            Annotated { annotation, doc, .. } => {
+                annotation.hash(state);
+                todo.push(doc.as_ref());
+            },
+            LineCount { header, body, .. } => {
+                header.hash(state);
+                todo.push(body.as_ref());
+            },
+            Alt { options, .. } => {
+                options.len().hash(state);
+                todo.extend(options.iter().rev().map(AnnotatedDoc::as_ref));
+            },
+            TabStop { width } => width.hash(state),
+            SetTab { name }
+            | TabTo { name } => name.hash(state),
+            Column { f }
+            | Nesting { f } => f.hash(state),
+        }
+    }
+}
+
+// Cloning only bumps the refcount, so it doesn't need `A : Clone`.
+impl<A : 'static> Clone for AnnotatedDoc<A> {
+    fn clone(&self) -> Self {
+        AnnotatedDoc(self.0.clone())
+    }
+}
 
 // Standard wadler-style pretty printer items. The only difference
 // between Newline and NewlineZero is that when printing in flatmode,
 // a Newline will be rendered as a space, and a NewlineZero (for zero-width)
 // will not insert a space.
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub enum InnerDoc {
+pub enum InnerDoc<A : 'static = ()> {
     Nil,
     Newline,
     NewlineZero,
+    // `s` is borrowed for text made with `static_text`, so that keywords
+    // and punctuation don't each cost an allocation.
     Text { 
-        s : String, 
+        s : Cow<'static, str>, 
         len : usize
     },
     Concat { 
-        lhs : Doc, 
-        rhs : Doc, 
+        lhs : AnnotatedDoc<A>, 
+        rhs : AnnotatedDoc<A>, 
         has_newline : bool, 
         dist_newline : usize,
         flat_len : usize,
     },
+    // A negative `nest` takes indentation away, down to none at all.
     Nest { 
-        nest : usize, 
-        doc : Doc, 
+        nest : isize, 
+        doc : AnnotatedDoc<A>, 
         has_newline : bool, 
         dist_newline : usize,
         flat_len : usize,
     },
     Group { 
-        doc : Doc, 
+        doc : AnnotatedDoc<A>, 
+        name : Option<&'static str>,
+        // Whether this came from `group_first_line`.
+        first_line : bool,
         has_newline : bool, 
         dist_newline : usize,
         flat_len : usize,
+    },
+    // A region that `render_partial` keeps track of, so its content can be
+    // swapped out after rendering. Lays out exactly like `doc`.
+    Placeholder {
+        id : PlaceholderId,
+        doc : AnnotatedDoc<A>,
+        has_newline : bool,
+        dist_newline : usize,
+        flat_len : usize,
+    },
+    // Lays out `doc` as if the line were `width` columns wide, instead of
+    // whatever width the surrounding document is rendered at.
+    WithWidth {
+        width : usize,
+        doc : AnnotatedDoc<A>,
+        has_newline : bool,
+        dist_newline : usize,
+        flat_len : usize,
+    },
+    // Lays out exactly like `doc`; `render_paginated` tries not to put a
+    // page break inside it.
+    KeepTogether {
+        doc : AnnotatedDoc<A>,
+        has_newline : bool,
+        dist_newline : usize,
+        flat_len : usize,
+    },
+    // Pads with spaces up to the next column that's a multiple of `width`
+    // (nothing if the column already is one). How much that is depends on
+    // where it lands at render time, so for measuring purposes it counts
+    // as the most it can ever be, `width - 1`.
+    TabStop {
+        width : usize,
+    },
+    // Remembers the column it lands at under `name`, for `TabTo`.
+    SetTab {
+        name : &'static str,
+    },
+    // Pads with spaces up to the column most recently recorded under
+    // `name` by a `SetTab` (nothing if there's none yet, or it's already
+    // past it). The padding isn't known until render time, and for
+    // measuring purposes it counts as nothing.
+    TabTo {
+        name : &'static str,
+    },
+    // Lays out exactly like `doc`, except that every newline inside it
+    // that's rendered as a break gets a ` \` in front of it. The line width
+    // inside is two less, so there's always room for it.
+    Continued {
+        doc : AnnotatedDoc<A>,
+        has_newline : bool,
+        dist_newline : usize,
+        flat_len : usize,
+    },
+    // `body`, or `summary` flattened if the render options say `id` is
+    // collapsed. Measured as `body`.
+    Fold {
+        id : FoldId,
+        summary : AnnotatedDoc<A>,
+        body : AnnotatedDoc<A>,
+        has_newline : bool,
+        dist_newline : usize,
+        flat_len : usize,
+    },
+    // Always a blank line. Its flat width is `usize::MAX`, as for
+    // `Hardline`.
+    UserBlankLine,
+    // A newline that's never flattened. Its flat width is `usize::MAX`, so
+    // no group around it ever fits.
+    Hardline,
+    // Laid out flat and written at the right margin of the line it's on,
+    // once the rest of the line is done. Takes up no room for measuring
+    // purposes.
+    RightSuffix {
+        content : AnnotatedDoc<A>,
+    },
+    // Laid out flat and held back until the line it's on ends, then
+    // written at the end of it. Takes up no room for measuring purposes.
+    LineSuffix {
+        doc : AnnotatedDoc<A>,
+    },
+    // Like `Nest`, except that what's inside is indented to the column the
+    // node starts at (plus `extra`), wherever that turns out to be.
+    Align {
+        extra : usize,
+        doc : AnnotatedDoc<A>,
+        has_newline : bool,
+        dist_newline : usize,
+        flat_len : usize,
+    },
+    // Lays out `doc` flat if it fits, and otherwise fills lines: each of
+    // `doc`'s own newlines is a space if what comes after it, up to the
+    // next one, still fits on the line, and a break otherwise. See `fill`.
+    Fill {
+        doc : AnnotatedDoc<A>,
+        has_newline : bool,
+        dist_newline : usize,
+        flat_len : usize,
+    },
+    // `doc`, followed by enough spaces to take it `width` columns past
+    // where it started. With `or_break`, one that's already got that far
+    // is followed by a `NewlineZero` nested by `width` columns instead.
+    // See `Doc::fill_break`.
+    Padded {
+        width : usize,
+        or_break : bool,
+        doc : AnnotatedDoc<A>,
+        has_newline : bool,
+        dist_newline : usize,
+        flat_len : usize,
+    },
+    // The first of `options` that fits, or the last one if none do. Always
+    // at least two of them; measured as the first. See `Doc::alt`.
+    Alt {
+        options : Vec<AnnotatedDoc<A>>,
+        has_newline : bool,
+        dist_newline : usize,
+        flat_len : usize,
+    },
+    // Whatever `f` makes of the column it lands at. For measuring purposes
+    // it counts as nothing, since what it is isn't known until render time.
+    Column {
+        f : DocFn<A>,
+    },
+    // Whatever `f` makes of the indentation level it's at. Measured like
+    // `Column`.
+    Nesting {
+        f : DocFn<A>,
+    },
+    // Lays out exactly like `doc`; `render_annotated` reports where it
+    // starts and ends.
+    Annotated {
+        annotation : A,
+        doc : AnnotatedDoc<A>,
+        has_newline : bool,
+        dist_newline : usize,
+        flat_len : usize,
+    },
+    // `body`, preceded by whatever `header` makes of the number of lines
+    // `body` takes up. See `Doc::with_line_count`.
+    LineCount {
+        header : DocFn<A>,
+        body : AnnotatedDoc<A>,
+        has_newline : bool,
+        dist_newline : usize,
+        flat_len : usize,
+    },
+}
+
+// A function that builds part of a document at render time, from a number
+// that isn't known until then (a line count, a column, an indentation
+// level). Functions can't be compared or printed, so two of these are only
+// equal if they're the same function (the same allocation), and `Debug`
+// doesn't show anything.
+pub struct DocFn<A : 'static>(pub Arc<dyn Fn(usize) -> AnnotatedDoc<A> + Send + Sync>);
+
+impl<A : 'static> Clone for DocFn<A> {
+    fn clone(&self) -> Self {
+        DocFn(self.0.clone())
+    }
+}
+
+impl<A> core::fmt::Debug for DocFn<A> {
+    fn fmt(&self, f : &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("DocFn(..)")
+    }
+}
+
+// By address, to go with `==`.
+impl<A : 'static> core::hash::Hash for DocFn<A> {
+    fn hash<H : core::hash::Hasher>(&self, state : &mut H) {
+        (Arc::as_ptr(&self.0) as *const ()).hash(state)
+    }
+}
+
+impl<A : 'static> PartialEq for DocFn<A> {
+    fn eq(&self, other : &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
     }
 }
 
+impl<A> Eq for DocFn<A> {}
 
-impl Doc {
+
+impl<A : 'static> AnnotatedDoc<A> {
     fn get_has_newline(&self) -> bool {
         match self.as_ref() {
             Nil                        => false,
             Newline | NewlineZero      => true,
+            UserBlankLine | Hardline   => true,
             Concat { has_newline, .. } => *has_newline,
             Nest   { has_newline, .. } => *has_newline,
             Group  { has_newline, .. } => *has_newline,
+            Placeholder { has_newline, .. } => *has_newline,
+            KeepTogether { has_newline, .. } => *has_newline,
+            WithWidth { has_newline, .. } => *has_newline,
+            LineCount { has_newline, .. } => *has_newline,
+            Continued { has_newline, .. } => *has_newline,
+            Annotated { has_newline, .. } => *has_newline,
+            Fill { has_newline, .. } => *has_newline,
+            Padded { has_newline, .. } => *has_newline,
+            Alt { has_newline, .. } => *has_newline,
+            Align { has_newline, .. } => *has_newline,
+            Fold { has_newline, .. } => *has_newline,
             Text   { .. }              => false,
+            TabStop { .. }             => false,
+            SetTab { .. } | TabTo { .. } => false,
+            RightSuffix { .. }         => false,
+            LineSuffix { .. }          => false,
+            Column { .. } | Nesting { .. } => false,
         }
     }
 
@@ -117,7 +970,19 @@ impl Doc {
             Concat { dist_newline, .. } => *dist_newline,
             Nest   { dist_newline, .. } => *dist_newline,
             Group  { dist_newline, .. } => *dist_newline,
+            Placeholder { dist_newline, .. } => *dist_newline,
+            KeepTogether { dist_newline, .. } => *dist_newline,
+            WithWidth { dist_newline, .. } => *dist_newline,
+            LineCount { dist_newline, .. } => *dist_newline,
+            Continued { dist_newline, .. } => *dist_newline,
+            Annotated { dist_newline, .. } => *dist_newline,
+            Fill { dist_newline, .. } => *dist_newline,
+            Padded { dist_newline, .. } => *dist_newline,
+            Alt { dist_newline, .. } => *dist_newline,
+            Align { dist_newline, .. } => *dist_newline,
+            Fold { dist_newline, .. } => *dist_newline,
             Text   { len, .. }          => *len,
+            TabStop { width }           => width.saturating_sub(1),
             _                           => 0
         }
     }
@@ -127,183 +992,3253 @@ impl Doc {
             Concat { flat_len, .. }     => *flat_len,
             Nest   { flat_len, .. }     => *flat_len,
             Group  { flat_len, .. }     => *flat_len,
+            Placeholder { flat_len, .. } => *flat_len,
+            KeepTogether { flat_len, .. } => *flat_len,
+            WithWidth { flat_len, .. } => *flat_len,
+            LineCount { flat_len, .. } => *flat_len,
+            Continued { flat_len, .. } => *flat_len,
+            Annotated { flat_len, .. } => *flat_len,
+            Fill { flat_len, .. } => *flat_len,
+            Padded { flat_len, .. } => *flat_len,
+            Alt { flat_len, .. } => *flat_len,
+            Align { flat_len, .. } => *flat_len,
+            Fold { flat_len, .. } => *flat_len,
             Text   { len, .. }          => *len,
+            TabStop { width }           => width.saturating_sub(1),
             Newline                     => 1,
+            Hardline | UserBlankLine    => usize::MAX,
             _                           => 0
         }
     }
+
+    // How wide the document is when laid out flat, i.e. all on one line.
+    // A document with a hard line break in it never goes flat, and counts
+    // as `usize::MAX` wide.
+    pub fn flat_width(&self) -> usize {
+        self.get_flat_len()
+    }
+
+    // Whether the flat layout fits in `width` columns.
+    pub fn fits_flat(&self, width : usize) -> bool {
+        self.flat_width() <= width
+    }
+
+    // The same answer as `fits_flat(budget)`, worked out by adding up the
+    // widths of the pieces of the document instead of reading the total
+    // cached at its root, and stopping as soon as they come to more than
+    // `budget`. Only concatenations and the nodes that lay their content
+    // out flat as it is (`nest`, `group`, `annotate`) are looked inside;
+    // anything else counts with its cached width. So for a document far
+    // wider than `budget` this only looks at about `budget` columns' worth
+    // of it. The render loop's group check doesn't need this, since it
+    // compares cached widths.
+    pub fn fits_within(&self, budget : usize) -> bool {
+        let mut used = 0usize;
+        let mut todo = vec![self];
+        while let Some(doc) = todo.pop() {
+            match doc.as_ref() {
+                // Whichever side is itself a concatenation goes on the
+                // stack first, so the other is taken off right away and
+                // the stack stays shallow whichever way the chain leans.
+                Concat { lhs, rhs, .. } => match lhs.as_ref() {
+                    Concat { .. } => {
+                        todo.push(lhs);
+                        todo.push(rhs);
+                    },
+                    _ => {
+                        todo.push(rhs);
+                        todo.push(lhs);
+                    },
+                },
+                Nest { doc, .. }
+                | Group { doc, .. }
+                | Annotated { doc, .. } => todo.push(doc),
+                _ => {
+                    used = used.saturating_add(doc.get_flat_len());
+                    if used > budget {
+                        return false
+                    }
+                },
+            }
+        }
+        true
+    }
+
+    // Whether the two documents have the same shape: the same nodes, with
+    // the same text, nesting amounts, names and annotations, in the same
+    // places. The measurements cached in each node are left out, since
+    // they follow from the rest. Functions (`column`, `with_line_count`,
+    // ...) are only equal if they're the same function. This is what
+    // `==` does for documents.
+    pub fn structurally_eq(&self, other : &Self) -> bool
+    where A : PartialEq {
+        let mut todo = vec![(self, other)];
+        while let Some((a, b)) = todo.pop() {
+            if Arc::ptr_eq(&a.0, &b.0) {
+                continue
+            }
+            let same = match (a.as_ref(), b.as_ref()) {
+                (Nil, Nil)
+                | (Newline, Newline)
+                | (NewlineZero, NewlineZero)
+                | (UserBlankLine, UserBlankLine)
+                | (Hardline, Hardline) => true,
+                (Text { s : s1, .. }, Text { s : s2, .. }) => s1 == s2,
+                (Concat { lhs : l1, rhs : r1, .. }, Concat { lhs : l2, rhs : r2, .. }) => {
+                    todo.push((r1, r2));
+                    todo.push((l1, l2));
+                    true
+                },
+                (Nest { nest : n1, doc : d1, .. }, Nest { nest : n2, doc : d2, .. }) => {
+                    todo.push((d1, d2));
+                    n1 == n2
+                },
+                (WithWidth { width : n1, doc : d1, .. }, WithWidth { width : n2, doc : d2, .. })
+                | (Align { extra : n1, doc : d1, .. }, Align { extra : n2, doc : d2, .. }) => {
+                    todo.push((d1, d2));
+                    n1 == n2
+                },
+                (Padded { width : w1, or_break : b1, doc : d1, .. }, Padded { width : w2, or_break : b2, doc : d2, .. }) => {
+                    todo.push((d1, d2));
+                    w1 == w2 && b1 == b2
+                },
+                (Group { doc : d1, name : n1, first_line : f1, .. }, Group { doc : d2, name : n2, first_line : f2, .. }) => {
+                    todo.push((d1, d2));
+                    n1 == n2 && f1 == f2
+                },
+                (Placeholder { id : i1, doc : d1, .. }, Placeholder { id : i2, doc : d2, .. }) => {
+                    todo.push((d1, d2));
+                    i1 == i2
+                },
+                (KeepTogether { doc : d1, .. }, KeepTogether { doc : d2, .. })
+                | (Continued { doc : d1, .. }, Continued { doc : d2, .. })
+                | (Fill { doc : d1, .. }, Fill { doc : d2, .. })
+                | (RightSuffix { content : d1 }, RightSuffix { content : d2 })
+                | (LineSuffix { doc : d1 }, LineSuffix { doc : d2 }) => {
+                    todo.push((d1, d2));
+                    true
+                },
+                (Fold { id : i1, summary : s1, body : b1, .. }, Fold { id : i2, summary : s2, body : b2, .. }) => {
+                    todo.push((b1, b2));
+                    todo.push((s1, s2));
+                    i1 == i2
+                },
+                (Annotated { annotation : a1, doc : d1, .. }, Annotated { annotation : a2, doc : d2, .. }) => {
+                    todo.push((d1, d2));
+                    a1 == a2
+                },
+                (LineCount { header : h1, body : b1, .. }, LineCount { header : h2, body : b2, .. }) => {
+                    todo.push((b1, b2));
+                    h1 == h2
+                },
+                (Alt { options : o1, .. }, Alt { options : o2, .. }) => {
+                    todo.extend(o1.iter().zip(o2.iter()).rev());
+                    o1.len() == o2.len()
+                },
+                (TabStop { width : w1 }, TabStop { width : w2 }) => w1 == w2,
+                (SetTab { name : n1 }, SetTab { name : n2 })
+                | (TabTo { name : n1 }, TabTo { name : n2 }) => n1 == n2,
+                (Column { f : f1 }, Column { f : f2 })
+                | (Nesting { f : f1 }, Nesting { f : f2 }) => f1 == f2,
+                _ => false,
+            };
+            if !same {
+                return false
+            }
+        }
+        true
+    }
  
+    // An outline of the document's tree, for looking into why a layout came
+    // out the way it did: one node per line, indented under its parent,
+    // with what's cached in it. E.g. for `Doc::from("foo").nest(2).group()`
+    //
+    //   Group [flat 3, dist 3]
+    //     Nest(2) [flat 3, dist 3]
+    //       Text("foo") [flat 3, dist 3]
+    //
+    // with `newline` in the brackets for a node that has one, and `inf`
+    // for a width that's never going to fit.
+    pub fn debug_tree(&self) -> String
+    where A : core::fmt::Debug {
+        fn amount(n : usize) -> String {
+            if n == usize::MAX { String::from("inf") } else { n.to_string() }
+        }
+
+        let mut acc = String::new();
+        let mut todo = vec![(self, 0)];
+        while let Some((doc, depth)) = todo.pop() {
+            let (label, children) : (String, Vec<&Self>) = match doc.as_ref() {
+                Nil                                  => (String::from("Nil"), vec![]),
+                Newline                              => (String::from("Newline"), vec![]),
+                NewlineZero                          => (String::from("NewlineZero"), vec![]),
+                UserBlankLine                        => (String::from("UserBlankLine"), vec![]),
+                Hardline                             => (String::from("Hardline"), vec![]),
+                Text { s, .. }                       => (format!("Text({:?})", s), vec![]),
+                Concat { lhs, rhs, .. }              => (String::from("Concat"), vec![lhs, rhs]),
+                Nest { nest, doc, .. }               => (format!("Nest({})", nest), vec![doc]),
+                Group { doc, name, first_line, .. }  => {
+                    let label = match (name, first_line) {
+                        (Some(n), _)  => format!("Group({:?})", n),
+                        (None, true)  => String::from("Group(first_line)"),
+                        (None, false) => String::from("Group"),
+                    };
+                    (label, vec![doc])
+                },
+                Placeholder { id, doc, .. }          => (format!("Placeholder({})", id.0), vec![doc]),
+                WithWidth { width, doc, .. }         => (format!("WithWidth({})", width), vec![doc]),
+                KeepTogether { doc, .. }             => (String::from("KeepTogether"), vec![doc]),
+                TabStop { width }                    => (format!("TabStop({})", width), vec![]),
+                SetTab { name }                      => (format!("SetTab({:?})", name), vec![]),
+                TabTo { name }                       => (format!("TabTo({:?})", name), vec![]),
+                Continued { doc, .. }                => (String::from("Continued"), vec![doc]),
+                Fold { id, summary, body, .. }       => (format!("Fold({})", id.0), vec![summary, body]),
+                RightSuffix { content }              => (String::from("RightSuffix"), vec![content]),
+                LineSuffix { doc }                   => (String::from("LineSuffix"), vec![doc]),
+                Align { extra, doc, .. }             => (format!("Align({})", extra), vec![doc]),
+                Fill { doc, .. }                     => (String::from("Fill"), vec![doc]),
+                Padded { width, or_break, doc, .. }  => {
+                    let label = if *or_break { "PaddedOrBreak" } else { "Padded" };
+                    (format!("{}({})", label, width), vec![doc])
+                },
+                Alt { options, .. }                  => (String::from("Alt"), options.iter().collect()),
+                Column { .. }                        => (String::from("Column(..)"), vec![]),
+                Nesting { .. }                       => (String::from("Nesting(..)"), vec![]),
+                Annotated { annotation, doc, .. }    => (format!("Annotated({:?})", annotation), vec![doc]),
+                LineCount { body, .. }               => (String::from("LineCount(..)"), vec![body]),
+            };
+            for _ in 0..depth {
+                acc.push_str("  ");
+            }
+            acc.push_str(&label);
+            acc.push_str(&format!(" [flat {}, dist {}", amount(doc.get_flat_len()), amount(doc.get_dist_newline())));
+            if doc.get_has_newline() {
+                acc.push_str(", newline");
+            }
+            acc.push_str("]\n");
+            todo.extend(children.into_iter().rev().map(|child| (child, depth + 1)));
+        }
+        acc
+    }
+
+    // The text of every `Text` atom in the document, left to right, without
+    // rendering it: no spaces for newlines, no indentation. Text that's
+    // only made at render time (by `column`, `nesting` or a line count
+    // header) isn't there to be found; for an `alt` only the first option
+    // is looked at, and for a fold only its body.
+    pub fn text_leaves(&self) -> impl Iterator<Item = &str> {
+        TextLeaves { todo : vec![self] }
+    }
+
+    // The total width of `text_leaves`.
+    pub fn plain_len(&self) -> usize {
+        self.text_leaves().map(display_width).sum()
+    }
+
     pub fn nil() -> Self {
-        Doc::from(Nil)
+        shared_leaf!(Nil)
     }
 
+    // `doc` if `cond` holds, and `nil` otherwise, for pieces that are only
+    // sometimes there (a trailing comma, a `pub`). Concatenating a `nil`
+    // leaves no trace, so a piece that's left out costs nothing.
+    pub fn when(cond : bool, doc : impl Into<Self>) -> Self {
+        if cond {
+            doc.into()
+        } else {
+            Self::nil()
+        }
+    }
+
+    // The document in `opt`, or `nil` if there isn't one.
+    pub fn from_option(opt : Option<Self>) -> Self {
+        opt.unwrap_or_else(Self::nil)
+    }
+
+    // A single unbreakable atom. It must not contain a newline, which the
+    // layout would count as part of the line's width instead of as a
+    // break; use `text_multiline` for text that might have some. Nor
+    // should it contain a tab, which gets counted as a single column (or
+    // none) however far it really moves the cursor; `text_expand_tabs`
+    // turns those into spaces first.
     pub fn text(s : String) -> Self {
-        let len = s.len();
-        Doc::from(Text { 
-            s,
+        debug_assert!(!s.contains('\n'), "Doc::text given a newline; use Doc::text_multiline");
+        let len = display_width(&s);
+        Self::from(Text { 
+            s : Cow::Owned(s),
             len
         })
    }
 
-    pub fn nest(&self, n : usize) -> Self {
-        Doc::from(Nest {
-            nest : n,
-            doc : self.clone(),
-            has_newline : self.get_has_newline(),
-            dist_newline : self.get_dist_newline(),
-            flat_len : self.get_flat_len(),
+    // `text`, for a string that's around for good (a keyword, an operator),
+    // which is used in place instead of being copied.
+    pub fn static_text(s : &'static str) -> Self {
+        debug_assert!(!s.contains('\n'), "Doc::static_text given a newline; use Doc::text_multiline");
+        Self::from(Text {
+            s : Cow::Borrowed(s),
+            len : display_width(s),
         })
-   }
+    }
 
-    pub fn concat(&self, other : impl Into<Doc>) -> Self {
-        let other : Doc = other.into();
-        Doc::from(Concat {
-             lhs : self.clone(),
-             rhs : other.clone(),
-             has_newline : (self.get_has_newline()) || (other.get_has_newline()),
-             dist_newline : if self.get_has_newline() {
-                 self.get_dist_newline()
-             } else {
-                 self.get_dist_newline() + other.get_dist_newline()
-             },
-             flat_len : self.get_flat_len() + other.get_flat_len(),
-         })
+    // Text that may have newlines in it: each line becomes a `text` atom,
+    // with a `newline` between them, so the later lines are indented like
+    // any others. A "\r\n" counts as one newline.
+    pub fn text_multiline(s : &str) -> Self {
+        let lines = s.split('\n').map(|line| {
+            Self::text(line.strip_suffix('\r').unwrap_or(line).to_string())
+        }).collect::<Vec<Self>>();
+        join(&lines, &Self::newline())
     }
 
-    // make (d1, newline, d2)
-    pub fn concat_newline(self, other : impl Into<Doc>) -> Doc {
-        self.concat(Newline)
-            .concat(other)
+    // `text`, with every tab in `s` replaced by spaces up to the next
+    // multiple of `tab_width`. The stops are counted from the start of `s`
+    // itself, not from wherever it ends up on the line, so a tab after
+    // some earlier text (or another tab) only goes as far as that text's
+    // next stop. A `tab_width` of zero just drops the tabs.
+    pub fn text_expand_tabs(s : &str, tab_width : usize) -> Self {
+        let mut expanded = String::with_capacity(s.len());
+        let mut col : usize = 0;
+        for c in s.chars() {
+            if c == '\t' {
+                if let Some(stops) = col.checked_div(tab_width) {
+                    let stop = (stops + 1) * tab_width;
+                    expanded.extend(core::iter::repeat_n(' ', stop - col));
+                    col = stop;
+                }
+            } else {
+                expanded.push(c);
+                col += UnicodeWidthChar::width(c).unwrap_or(0);
+            }
+        }
+        Self::text(expanded)
     }
 
-    // make (d1, space, d2)
-    pub fn concat_space(self, other : impl Into<Doc>) -> Doc {
-        self.concat(format!(" "))
-            .concat(other)
+    // A single space.
+    pub fn space() -> Self {
+        shared_leaf!(text " ")
     }
 
-    pub fn group(&self) -> Self {
-        Doc::from(Group {
-            doc : self.clone(),
-            has_newline : self.get_has_newline(),
-            dist_newline : self.get_dist_newline(),
-            flat_len : self.get_flat_len(),
-        })
+    // `n` spaces, as a single `Text` atom.
+    pub fn spaces(n : usize) -> Self {
+        Self::text_repeat(' ', n)
     }
 
+    // `n` copies of `c` as a single `Text` atom, e.g. a `----` rule, for
+    // the price of one node and one allocation.
+    pub fn text_repeat(c : char, n : usize) -> Self {
+        let s = core::iter::repeat_n(c, n).collect::<String>();
+        let len = display_width(c.encode_utf8(&mut [0; 4])).saturating_mul(n);
+        Self::from(Text {
+            s : Cow::Owned(s),
+            len
+        })
+    }
 
-    pub fn line() -> Self {
-        Doc::from(Newline)
+    // `s` as a quoted string literal, in the default `QuoteStyle`: `a"b`
+    // becomes `"a\"b"`, and a newline in it becomes `\n`. It's a single
+    // `Text` atom, and its width is that of the escaped text.
+    pub fn string_literal(s : &str) -> Self {
+        Self::string_literal_with(s, &QuoteStyle::default())
     }
 
-    pub fn newline() -> Self {
-        Doc::from(Newline)
+    // `string_literal`, quoted and escaped as `style` says.
+    pub fn string_literal_with(s : &str, style : &QuoteStyle) -> Self {
+        let mut escaped = String::with_capacity(s.len() + 2);
+        escaped.push(style.quote);
+        for c in s.chars() {
+            match c {
+                '\\' => escaped.push_str("\\\\"),
+                '\n' => escaped.push_str("\\n"),
+                '\r' => escaped.push_str("\\r"),
+                '\t' => escaped.push_str("\\t"),
+                '\0' => escaped.push_str("\\0"),
+                c if c == style.quote => {
+                    escaped.push('\\');
+                    escaped.push(c);
+                },
+                c if c.is_control() || (style.escape_non_ascii && !c.is_ascii()) => {
+                    escaped.push_str(&format!("\\u{{{:x}}}", c as u32));
+                },
+                c => escaped.push(c),
+            }
+        }
+        escaped.push(style.quote);
+        Self::text(escaped)
     }
 
-    pub fn newline_zero() -> Self {
-        Doc::from(NewlineZero)
+    // A `/* ... */` comment as a single `Text` atom. The layout can move
+    // it around as a unit (e.g. onto the next line of an argument list)
+    // but will never break inside it, so it can't end up unterminated.
+    // Runs of whitespace in `body`, newlines included, collapse to a
+    // single space to keep the atom on one line with an honest width.
+    // For a comment that may be too long for the line, see `wrap_comment`.
+    pub fn block_comment(body : &str) -> Self {
+        let words = body.split_whitespace().collect::<Vec<&str>>();
+        if words.is_empty() {
+            Self::static_text("/* */")
+        } else {
+            Self::text(format!("/* {} */", words.join(" ")))
+        }
     }
 
-    pub fn surround_paren(self) -> Self {
-        Doc::from("(")
-        .concat(self)
-        .concat(")")
+    // `block_comment`, reflowed to fit when it's too long for the line: the
+    // words are filled in one at a time, and each line after the first
+    // starts with ` * ` under the `/*`, the way a wrapped block comment is
+    // written by hand:
+    //
+    //   /* one two
+    //    * three four
+    //    * five */
+    //
+    // Where it fits, or inside a group that's flat, it's exactly the
+    // `block_comment`, and that's how enclosing groups measure it.
+    pub fn wrap_comment(body : &str) -> Self {
+        let mut words = body.split_whitespace();
+        let Some(first) = words.next() else {
+            return Self::block_comment(body)
+        };
+        // ` * ` where a word starts a line, which is where the break in
+        // front of it leaves the cursor when it's taken, and nothing where
+        // the line is still going.
+        let star = || Self::nesting(|nest| Self::column(move |col| if col == nest {
+            Self::static_text(" * ")
+        } else {
+            Self::nil()
+        }));
+        let mut pieces = core::iter::once(Self::static_text("/* ").concat(first.to_string()))
+                         .chain(words.map(|word| star().concat(word.to_string())))
+                         .collect::<Vec<Self>>();
+        if let Some(last) = pieces.pop() {
+            pieces.push(last.concat(Self::static_text(" */")));
+        }
+        word_wrap_val(pieces.into_iter()).align()
     }
 
-    pub fn surround_curly(self) -> Self {
-        Doc::from("{")
+    pub fn nest(&self, n : usize) -> Self {
+        self.nest_by(isize::try_from(n).unwrap_or(isize::MAX))
+    }
+
+    // Take `n` off the indentation of every line the document starts, e.g.
+    // so a closing `}` lines up with its opener rather than the body. The
+    // indentation stops at zero rather than going negative.
+    pub fn dedent(&self, n : usize) -> Self {
+        self.nest_by(isize::try_from(n).map_or(isize::MIN, |n| -n))
+    }
+
+    // The document as a block of its own: a newline, then the document,
+    // with the newline and every line break in the document nested by `n`,
+    // so each of its lines is indented by `n` more than the line it starts
+    // from. It doesn't group anything itself. Inside a group that breaks,
+    // or with no group around it, the block starts on a new line; inside
+    // one that goes flat, the newline is just a space (and the nesting
+    // does nothing), so
+    //
+    //   Doc::from("Foo {").concat(fields.indent(2)).concat(Doc::newline()).concat(Doc::from("}")).group()
+    //
+    // is `Foo { a, b }` if that fits, and the fields on lines of their own,
+    // indented by 2, with the `}` back under `Foo`, otherwise.
+    pub fn indent(self, n : usize) -> Self {
+        Self::newline().concat(self).nest(n)
+    }
+
+    fn nest_by(&self, n : isize) -> Self {
+        Self::from(Nest {
+            nest : n,
+            doc : self.clone(),
+            has_newline : self.get_has_newline(),
+            dist_newline : self.get_dist_newline(),
+            flat_len : self.get_flat_len(),
+        })
+   }
+
+    // The result's left side is `self`, so appending in a loop
+    // (`acc = acc.concat(next)`) makes a tree that leans left, and
+    // prepending (`next.concat(acc)`) one that leans right. Either lays
+    // out the same, and renders in time linear in its size; `balance`
+    // makes the shape the same too, if that matters.
+    pub fn concat(&self, other : impl Into<Self>) -> Self {
+        Self::concat_owned(self.clone(), other.into())
+    }
+
+    // The same document, with the chain of `Concat`s at the top of it
+    // rebuilt to lean left, the way appending would have made it, however
+    // it was really put together. The pieces themselves (anything that
+    // isn't a `Concat`) are shared, not copied, and nothing inside them
+    // is touched. What this buys is a document whose shape doesn't depend
+    // on the order it was built in, e.g. for comparing trees with
+    // `debug_tree` or `structurally_eq`. It doesn't make rendering any
+    // faster: a long chain that leans left renders about a quarter slower
+    // than one that leans right (see benches/balance.rs), since the
+    // render loop holds the right-hand sides of the spine on its stack.
+    pub fn balance(&self) -> Self {
+        let mut pieces = Vec::new();
+        let mut todo = vec![self];
+        while let Some(doc) = todo.pop() {
+            match doc.as_ref() {
+                Concat { lhs, rhs, .. } => {
+                    todo.push(rhs);
+                    todo.push(lhs);
+                },
+                _ => pieces.push(doc.clone()),
+            }
+        }
+        pieces.into_iter().fold(Self::nil(), Self::concat_owned)
+    }
+
+    // `concat`, for when both halves are already owned and can be moved
+    // into their new parent. A `nil` half is dropped rather than given a
+    // node, so folding from `nil` or leaving out optional pieces doesn't
+    // leave the tree full of empty `Concat`s.
+    fn concat_owned(lhs : Self, rhs : Self) -> Self {
+        if matches!(lhs.as_ref(), Nil) {
+            return rhs
+        } else if matches!(rhs.as_ref(), Nil) {
+            return lhs
+        }
+        let has_newline = lhs.get_has_newline() || rhs.get_has_newline();
+        let dist_newline = if lhs.get_has_newline() {
+            lhs.get_dist_newline()
+        } else {
+            lhs.get_dist_newline().saturating_add(rhs.get_dist_newline())
+        };
+        let flat_len = lhs.get_flat_len().saturating_add(rhs.get_flat_len());
+        Self::from(Concat {
+             lhs,
+             rhs,
+             has_newline,
+             dist_newline,
+             flat_len,
+         })
+    }
+
+    // make (d1, newline, d2)
+    pub fn concat_newline(self, other : impl Into<Self>) -> Self {
+        self.concat(Newline)
+            .concat(other)
+    }
+
+    // make (d1, newline_zero, d2): `concat_newline` for when `d1` or `d2`
+    // already brings its own spacing, so that flat it's just the two
+    // side by side, `foo,bar`, and broken it's `foo,` and `bar` on lines
+    // of their own.
+    pub fn concat_line_zero(self, other : impl Into<Self>) -> Self {
+        self.concat(NewlineZero)
+            .concat(other)
+    }
+
+    // make (d1, space, d2)
+    pub fn concat_space(self, other : impl Into<Self>) -> Self {
+        self.concat(Self::space())
+            .concat(other)
+    }
+
+    // `n` copies of the document, one after another (`nil` for 0). The
+    // copies share the one document rather than duplicating it.
+    pub fn repeat(&self, n : usize) -> Self {
+        core::iter::repeat_n(self.clone(), n).collect()
+    }
+
+    // Pad the document with spaces out to `width` columns from where it
+    // starts, or if it's already that wide, put a break after it, nested
+    // by `width` columns. E.g. to line up the types in a list of fields,
+    //
+    //   name    : String
+    //   age     : u32
+    //   nickname
+    //           : Option<String>
+    //
+    // each label is `fill_break(8)`ed and followed by `": "` and the type.
+    // It's a `NewlineZero` that's added, so inside a group that goes flat
+    // the wide label just runs into what follows it. Whether to pad or
+    // break is settled when the end of the document is reached, by the
+    // column it got to; for one that spans lines that's the column on the
+    // last of them, still measured from where the document started.
+    pub fn fill_break(self, width : usize) -> Self {
+        self.padded(width, true)
+    }
+
+    // Pad the document with spaces out to `width` columns from where it
+    // starts, and leave it as it is if it's already that wide; e.g. for
+    // the cells of a table whose columns are known to be wide enough. For
+    // a document that spans lines it's the last line that's padded, up to
+    // the column `width` past where the document started, not `width`
+    // past the start of that line.
+    pub fn fill(self, width : usize) -> Self {
+        self.padded(width, false)
+    }
+
+    fn padded(self, width : usize, or_break : bool) -> Self {
+        let flat_len = self.get_flat_len().max(width);
+        let breaks_after = or_break && self.get_flat_len() >= width;
+        let (has_newline, dist_newline) = if self.get_has_newline() {
+            (true, self.get_dist_newline())
+        } else if breaks_after {
+            (true, self.get_flat_len())
+        } else {
+            (false, flat_len)
+        };
+        Self::from(Padded {
+            width,
+            or_break,
+            has_newline,
+            dist_newline,
+            flat_len,
+            doc : self,
+        })
+    }
+
+    // A document that depends on where it lands: `f` is called at render
+    // time with the column it starts at, and what it returns is rendered
+    // there. E.g. padding a trailing comment out to a fixed column. Layout
+    // decisions made before it's reached count it as taking up no room.
+    pub fn column<F>(f : F) -> Self
+    where F : Fn(usize) -> Self + Send + Sync + 'static {
+        Self::from(Column { f : DocFn(Arc::new(f)) })
+    }
+
+    // Like `column`, but `f` is given the current indentation level.
+    pub fn nesting<F>(f : F) -> Self
+    where F : Fn(usize) -> Self + Send + Sync + 'static {
+        Self::from(Nesting { f : DocFn(Arc::new(f)) })
+    }
+
+    // Indent the lines after the first to the column this document
+    // starts at, instead of by a fixed amount like `nest` does; e.g. to
+    // line up the arms of a `match` under its scrutinee. The column isn't
+    // known until render time, so that's when it's worked out.
+    pub fn align(self) -> Self {
+        self.hang(0)
+    }
+
+    // `align`, plus `n` more columns.
+    pub fn hang(self, n : usize) -> Self {
+        Self::from(Align {
+            extra : n,
+            has_newline : self.get_has_newline(),
+            dist_newline : self.get_dist_newline(),
+            flat_len : self.get_flat_len(),
+            doc : self,
+        })
+    }
+
+    pub fn group(&self) -> Self {
+        Self::from(Group {
+            doc : self.clone(),
+            name : None,
+            first_line : false,
+            has_newline : self.get_has_newline(),
+            dist_newline : self.get_dist_newline(),
+            flat_len : self.get_flat_len(),
+        })
+    }
+
+    // A group whose lines after the first, if it breaks, are indented to
+    // the column the group started at rather than by its nesting; i.e.
+    // `align().group()`. For the arguments of a call,
+    //
+    //   foo(aaaa,
+    //       bbbb,
+    //       cccc)
+    //
+    // it's `"foo("` followed by the `group_align`ed arguments.
+    pub fn group_align(self) -> Self {
+        self.align().group()
+    }
+
+    // A group that can be singled out by name, e.g. to force its layout
+    // through `RenderOptions::group_overrides`. Otherwise it's the same as
+    // `group`.
+    pub fn group_named(&self, name : &'static str) -> Self {
+        Self::from(Group {
+            doc : self.clone(),
+            name : Some(name),
+            first_line : false,
+            has_newline : self.get_has_newline(),
+            dist_newline : self.get_dist_newline(),
+            flat_len : self.get_flat_len(),
+        })
+    }
+
+    // Several candidate layouts, best first; the first one that fits where
+    // it lands is the one rendered, and the last one if none of them do.
+    // An option fits if its first line does, which for an option without
+    // newlines means all of it (plus what follows it up to the next break,
+    // as for a group). Inside a group that's rendering flat it's always
+    // the first option, and that's also how enclosing groups measure it,
+    // so the first option should be the flattest. `nil` for no options.
+    pub fn alt(options : Vec<Self>) -> Self {
+        if options.len() < 2 {
+            return options.into_iter().next().unwrap_or_else(Self::nil)
+        }
+        Self::from(Alt {
+            has_newline : options[0].get_has_newline(),
+            dist_newline : options[0].get_dist_newline(),
+            flat_len : options[0].get_flat_len(),
+            options,
+        })
+    }
+
+    // The document laid out as if every group fit: each `Newline` is
+    // replaced by a space and each `NewlineZero` by nothing, and groups
+    // (and the other wrappers that only matter for deciding where to
+    // break) are dropped, so the result comes out the same at any width.
+    // Hard line breaks are left alone, as are the nestings and alignments
+    // that set their indentation, and a `right_align_suffix` is still
+    // aligned to the width it's rendered at.
+    pub fn flatten(&self) -> Self
+    where A : Clone + 'static {
+        // Children are flattened before their parent is rebuilt around
+        // them, with an explicit stack so a deep document can't overflow
+        // the real one: `Visit` pushes a node's flattened form onto `done`,
+        // or schedules its children and then a `Rebuild` that takes their
+        // flattened forms back off.
+        enum Step<'d, A : 'static> {
+            Visit(&'d AnnotatedDoc<A>),
+            Rebuild(&'d AnnotatedDoc<A>),
+        }
+        let mut todo = vec![Step::Visit(self)];
+        let mut done : Vec<Self> = Vec::new();
+        let take = |done : &mut Vec<Self>| done.pop().expect("a flattened child for each child");
+        while let Some(step) = todo.pop() {
+            let doc = match step {
+                Step::Visit(doc) => doc,
+                Step::Rebuild(doc) => {
+                    let rebuilt = match doc.as_ref() {
+                        Concat { .. } => {
+                            let rhs = take(&mut done);
+                            take(&mut done).concat(rhs)
+                        },
+                        Fold { id, .. } => {
+                            let body = take(&mut done);
+                            Self::foldable(*id, take(&mut done), body)
+                        },
+                        Nest { nest, .. }             => take(&mut done).nest_by(*nest),
+                        Placeholder { id, .. }        => Self::placeholder(*id, take(&mut done)),
+                        KeepTogether { .. }           => Self::keep_together(take(&mut done)),
+                        RightSuffix { .. }            => Self::right_align_suffix(take(&mut done)),
+                        LineSuffix { .. }             => take(&mut done).line_suffix(),
+                        Align { extra, .. }           => take(&mut done).hang(*extra),
+                        Padded { width, .. }          => take(&mut done).padded(*width, false),
+                        Annotated { annotation, .. }  => take(&mut done).annotate(annotation.clone()),
+                        LineCount { header, .. }      => {
+                            let header = header.clone();
+                            Self::with_line_count(move |lines| (header.0)(lines).flatten(), take(&mut done))
+                        },
+                        _ => unreachable!("only nodes with children are rebuilt"),
+                    };
+                    done.push(rebuilt);
+                    continue
+                },
+            };
+            match doc.as_ref() {
+                Newline                              => done.push(Self::space()),
+                NewlineZero                          => done.push(Self::nil()),
+                Group { doc : inner, .. }
+                | WithWidth { doc : inner, .. }
+                | Continued { doc : inner, .. }
+                | Fill { doc : inner, .. }           => todo.push(Step::Visit(inner)),
+                Alt { options, .. }                  => todo.push(Step::Visit(&options[0])),
+                Column { f }                         => {
+                    let f = f.clone();
+                    done.push(Self::column(move |col| (f.0)(col).flatten()))
+                },
+                Nesting { f }                        => {
+                    let f = f.clone();
+                    done.push(Self::nesting(move |nest| (f.0)(nest).flatten()))
+                },
+                Nil
+                | Text { .. }
+                | TabStop { .. }
+                | SetTab { .. }
+                | TabTo { .. }
+                | UserBlankLine
+                | Hardline                           => done.push(doc.clone()),
+                Concat { .. }
+                | Fold { .. }
+                | Nest { .. }
+                | Placeholder { .. }
+                | KeepTogether { .. }
+                | RightSuffix { .. }
+                | LineSuffix { .. }
+                | Align { .. }
+                | Padded { .. }
+                | Annotated { .. }
+                | LineCount { .. }                   => {
+                    todo.push(Step::Rebuild(doc));
+                    todo.extend(doc.as_ref().children().into_iter().rev().map(Step::Visit));
+                },
+            }
+        }
+        take(&mut done)
+    }
+
+    // The same document with the string of every `Text` replaced by what
+    // `f` makes of it, and its width measured again; everything else,
+    // breaks, nesting and groups included, is left as it is. `f` only ever
+    // sees the text of a single line, since line breaks are nodes of
+    // their own, and it mustn't add any. Text that `column`, `nesting` or
+    // `with_line_count` make at render time gets mapped too.
+    pub fn map_text<F>(&self, f : F) -> Self
+    where F : Fn(&str) -> String + Send + Sync + 'static,
+          A : Clone + 'static {
+        let f : Arc<dyn Fn(&str) -> String + Send + Sync> = Arc::new(f);
+        self.map_text_shared(&f)
+    }
+
+    // `map_text`, with `f` in a form that the closures it wraps around
+    // `Column` and friends can hold on to.
+    fn map_text_shared(&self, f : &Arc<dyn Fn(&str) -> String + Send + Sync>) -> Self
+    where A : Clone + 'static {
+        let leaf = |doc : &Self| match doc.as_ref() {
+            Text { s, .. }       => Some(Self::text(f(s))),
+            Column { f : col_f } => {
+                let (col_f, f) = (col_f.clone(), f.clone());
+                Some(Self::column(move |col| (col_f.0)(col).map_text_shared(&f)))
+            },
+            Nesting { f : nest_f } => {
+                let (nest_f, f) = (nest_f.clone(), f.clone());
+                Some(Self::nesting(move |nest| (nest_f.0)(nest).map_text_shared(&f)))
+            },
+            _ => None,
+        };
+        let header = |header : &DocFn<A>| {
+            let (header, f) = (header.clone(), f.clone());
+            DocFn(Arc::new(move |lines| (header.0)(lines).map_text_shared(&f)))
+        };
+        self.rebuild(leaf, false, Self::concat_owned, header)
+    }
+
+    // The same document with fewer nodes: wherever two `Text`s end up next
+    // to each other, they're merged into one, e.g. a word that was built
+    // up a character at a time. It renders exactly the same, at any
+    // width, since the merged text is measured as the two widths added
+    // up. The difference is in what sees the text atom by atom:
+    // `text_leaves`, `render_map_text` and `map_text` get the merged
+    // text, so one that looks for a particular word should run first.
+    pub fn optimize(&self) -> Self
+    where A : Clone + 'static {
+        self.rebuild(|_| None, true, concat_merging_texts, DocFn::clone)
+    }
+
+    // Rebuild the document from the bottom up, with the same constructors
+    // that made it. `leaf` gets the first go at every node: if it returns
+    // a replacement, that's used as is, and otherwise the node's children
+    // are rebuilt and it's put back together around them, with `join` for
+    // a `Concat` and `header` for the header of a `LineCount`. With
+    // `spines`, a whole chain of `Concat`s is rebuilt at once instead, by
+    // `join`ing its pieces in order from the left, whatever shape it had.
+    // An explicit stack takes the place of recursion, so a deep document
+    // can't overflow the real one.
+    fn rebuild<L, J, H>(&self, leaf : L, spines : bool, join : J, header : H) -> Self
+    where L : Fn(&Self) -> Option<Self>,
+          J : Fn(Self, Self) -> Self,
+          H : Fn(&DocFn<A>) -> DocFn<A>,
+          A : Clone + 'static {
+        enum Step<'d, A : 'static> {
+            Visit(&'d AnnotatedDoc<A>),
+            Rebuild(&'d AnnotatedDoc<A>),
+            // Join the last this many rebuilt pieces of a `Concat` chain.
+            Join(usize),
+        }
+        let mut todo = vec![Step::Visit(self)];
+        let mut done : Vec<Self> = Vec::new();
+        let take = |done : &mut Vec<Self>| done.pop().expect("a rebuilt child for each child");
+        while let Some(step) = todo.pop() {
+            let doc = match step {
+                Step::Visit(doc) => doc,
+                Step::Join(n) => {
+                    let pieces = done.split_off(done.len() - n);
+                    done.push(pieces.into_iter().fold(Self::nil(), &join));
+                    continue
+                },
+                Step::Rebuild(doc) => {
+                    let rebuilt = match doc.as_ref() {
+                        Concat { .. } => {
+                            let rhs = take(&mut done);
+                            join(take(&mut done), rhs)
+                        },
+                        Fold { id, .. } => {
+                            let body = take(&mut done);
+                            Self::foldable(*id, take(&mut done), body)
+                        },
+                        Alt { options, .. } => {
+                            let rebuilt = done.split_off(done.len() - options.len());
+                            Self::alt(rebuilt)
+                        },
+                        Group { name, first_line, .. } => {
+                            let inner = take(&mut done);
+                            Self::from(Group {
+                                name : *name,
+                                first_line : *first_line,
+                                has_newline : inner.get_has_newline(),
+                                dist_newline : inner.get_dist_newline(),
+                                flat_len : inner.get_flat_len(),
+                                doc : inner,
+                            })
+                        },
+                        Fill { .. } => {
+                            let inner = take(&mut done);
+                            Self::from(Fill {
+                                has_newline : inner.get_has_newline(),
+                                dist_newline : inner.get_dist_newline(),
+                                flat_len : inner.get_flat_len(),
+                                doc : inner,
+                            })
+                        },
+                        Nest { nest, .. }              => take(&mut done).nest_by(*nest),
+                        Placeholder { id, .. }         => Self::placeholder(*id, take(&mut done)),
+                        WithWidth { width, .. }        => Self::with_width(*width, take(&mut done)),
+                        KeepTogether { .. }            => Self::keep_together(take(&mut done)),
+                        Continued { .. }               => Self::continued(take(&mut done)),
+                        RightSuffix { .. }             => Self::right_align_suffix(take(&mut done)),
+                        LineSuffix { .. }              => take(&mut done).line_suffix(),
+                        Align { extra, .. }            => take(&mut done).hang(*extra),
+                        Padded { width, or_break, .. } => take(&mut done).padded(*width, *or_break),
+                        Annotated { annotation, .. }   => take(&mut done).annotate(annotation.clone()),
+                        LineCount { header : h, .. }   => {
+                            let h = header(h);
+                            Self::with_line_count(move |lines| (h.0)(lines), take(&mut done))
+                        },
+                        _ => unreachable!("only nodes with children are rebuilt"),
+                    };
+                    done.push(rebuilt);
+                    continue
+                },
+            };
+            if let Some(replacement) = leaf(doc) {
+                done.push(replacement);
+                continue
+            }
+            if spines && matches!(doc.as_ref(), Concat { .. }) {
+                let mut pieces = Vec::new();
+                let mut spine = vec![doc];
+                while let Some(doc) = spine.pop() {
+                    match doc.as_ref() {
+                        Concat { lhs, rhs, .. } => {
+                            spine.push(rhs);
+                            spine.push(lhs);
+                        },
+                        _ => pieces.push(doc),
+                    }
+                }
+                todo.push(Step::Join(pieces.len()));
+                todo.extend(pieces.into_iter().rev().map(Step::Visit));
+                continue
+            }
+            let children = doc.as_ref().children();
+            if children.is_empty() {
+                done.push(doc.clone());
+            } else {
+                todo.push(Step::Rebuild(doc));
+                todo.extend(children.into_iter().rev().map(Step::Visit));
+            }
+        }
+        take(&mut done)
+    }
+
+    // Mark `default` as a region to be filled in later; see
+    // `render_partial`.
+    pub fn placeholder(id : PlaceholderId, default : Self) -> Self {
+        Self::from(Placeholder {
+            id,
+            has_newline : default.get_has_newline(),
+            dist_newline : default.get_dist_newline(),
+            flat_len : default.get_flat_len(),
+            doc : default,
+        })
+    }
+
+    // The document with the content of every placeholder named `id`
+    // swapped for `content`, including ones that functions (`column` and
+    // the like) make at render time.
+    pub(crate) fn fill_placeholder(&self, id : PlaceholderId, content : &Self) -> Self
+    where A : Clone + Send + Sync {
+        let leaf = |doc : &Self| match doc.as_ref() {
+            Placeholder { id : found, .. } if *found == id => Some(Self::placeholder(id, content.clone())),
+            Column { f } => {
+                let (f, content) = (f.clone(), content.clone());
+                Some(Self::column(move |col| (f.0)(col).fill_placeholder(id, &content)))
+            },
+            Nesting { f } => {
+                let (f, content) = (f.clone(), content.clone());
+                Some(Self::nesting(move |nest| (f.0)(nest).fill_placeholder(id, &content)))
+            },
+            _ => None,
+        };
+        let header = |header : &DocFn<A>| {
+            let (header, content) = (header.clone(), content.clone());
+            DocFn(Arc::new(move |lines| (header.0)(lines).fill_placeholder(id, &content)))
+        };
+        self.rebuild(leaf, false, Self::concat_owned, header)
+    }
+
+    // The content of a `Fill`, cut at its top-level `Newline`s into the
+    // pieces of each element (and its separator), or `None` if any of them
+    // has a line break of its own.
+    fn fill_segments(&self) -> Option<Vec<Vec<Self>>> {
+        let mut segments = vec![Vec::new()];
+        let mut todo = vec![self];
+        while let Some(doc) = todo.pop() {
+            match doc.as_ref() {
+                Concat { lhs, rhs, .. } => {
+                    todo.push(rhs);
+                    todo.push(lhs);
+                },
+                Newline => segments.push(Vec::new()),
+                _ if doc.get_has_newline() => return None,
+                _ => segments.last_mut()?.push(doc.clone()),
+            }
+        }
+        Some(segments)
+    }
+
+    // Lay out `inner` against a line width of `width` rather than the
+    // width the whole document is rendered at, e.g. so a code block in an
+    // 80-column document can use 120 columns. Columns are still counted
+    // from the start of the line, and everything outside `inner` goes
+    // back to the surrounding width.
+    pub fn with_width(width : usize, inner : Self) -> Self {
+        Self::from(WithWidth {
+            width,
+            has_newline : inner.get_has_newline(),
+            dist_newline : inner.get_dist_newline(),
+            flat_len : inner.get_flat_len(),
+            doc : inner,
+        })
+    }
+
+    // Ask `render_paginated` to keep all the lines of `inner` on the same
+    // page, if they fit on one. Has no effect on the layout otherwise.
+    pub fn keep_together(inner : Self) -> Self {
+        Self::from(KeepTogether {
+            has_newline : inner.get_has_newline(),
+            dist_newline : inner.get_dist_newline(),
+            flat_len : inner.get_flat_len(),
+            doc : inner,
+        })
+    }
+
+    // Pad to the next tab stop (a multiple of `width` columns) with
+    // spaces. Unlike a literal '\t' this lines up the same way in every
+    // viewer, and the layout knows exactly how wide it turned out to be.
+    pub fn snap_to_tab(width : usize) -> Self {
+        Self::from(TabStop { width })
+    }
+
+    // Record the current column under `name`, so a later `tab_to(name)`,
+    // e.g. in the next row of a table, can line up with it. Produces no
+    // output of its own.
+    pub fn set_tab(name : &'static str) -> Self {
+        Self::from(SetTab { name })
+    }
+
+    // Pad with spaces to the column last recorded by `set_tab(name)`.
+    // Does nothing if `name` hasn't been set yet or the output is already
+    // at or past that column. The tab positions are tracked over the
+    // course of a single render, in output order.
+    pub fn tab_to(name : &'static str) -> Self {
+        Self::from(TabTo { name })
+    }
+
+    // A group with a more lenient fit check. When the whole group doesn't
+    // fit flat, rather than break every one of its newlines, it checks
+    // whether its *first* line (everything up to its first newline) fits.
+    // If so, it's laid out "fill" style: each of its newlines stays a
+    // space for as long as the content after it, up to the next newline,
+    // still fits, and only turns into a real break once it doesn't. If
+    // not even the first line fits, it breaks like a normal group. Groups
+    // nested inside make their own decisions as usual.
+    pub fn group_first_line(&self) -> Self {
+        Self::from(Group {
+            doc : self.clone(),
+            name : None,
+            first_line : true,
+            has_newline : self.get_has_newline(),
+            dist_newline : self.get_dist_newline(),
+            flat_len : self.get_flat_len(),
+        })
+    }
+
+    // `body`, preceded by `f` of the number of lines `body` ends up taking
+    // when it's rendered; e.g. a "// 42 lines" comment over a long block.
+    // The count isn't known until layout happens, so it's worked out at
+    // render time: `body` is laid out once on its own, starting from where
+    // the header starts, its lines are counted, and then `f(count)` and
+    // `body` are rendered as usual. That assumes the header doesn't change
+    // how `body` is laid out, which holds when it ends with a newline. For
+    // measuring purposes (whether an enclosing group fits, and so on), the
+    // header counts as `f(1)`, which is what it is when everything is flat.
+    pub fn with_line_count<F>(f : F, body : Self) -> Self
+    where F : Fn(usize) -> Self + Send + Sync + 'static {
+        let flat = f(1).concat(body.clone());
+        Self::from(LineCount {
+            header : DocFn(Arc::new(f)),
+            body,
+            has_newline : flat.get_has_newline(),
+            dist_newline : flat.get_dist_newline(),
+            flat_len : flat.get_flat_len(),
+        })
+    }
+
+    // For shell scripts and Makefiles, where a logical line that's broken
+    // across several physical ones needs a trailing backslash on all but
+    // the last: every newline in `inner` that ends up as a break is
+    // rendered as ` \` and then the break. Room for the ` \` is kept on
+    // every line, so `inner` is laid out two columns narrower. A
+    // `hardline` doesn't get one, since it's meant to separate lines.
+    pub fn continued(inner : Self) -> Self {
+        Self::from(Continued {
+            has_newline : inner.get_has_newline(),
+            dist_newline : inner.get_dist_newline(),
+            flat_len : inner.get_flat_len(),
+            doc : inner,
+        })
+    }
+
+    // A region that can be folded away, like in an editor: it renders as
+    // `body` normally, and as `summary` laid out flat (e.g. `{ ... }`) when
+    // `id` is in `RenderOptions::collapsed`. Whatever encloses it is laid
+    // out as though it were `body` either way, so collapsing a fold
+    // doesn't change the layout around it. `render_detailed` reports where
+    // each fold ended up.
+    pub fn foldable(id : FoldId, summary : Self, body : Self) -> Self {
+        Self::from(Fold {
+            id,
+            summary,
+            has_newline : body.get_has_newline(),
+            dist_newline : body.get_dist_newline(),
+            flat_len : body.get_flat_len(),
+            body,
+        })
+    }
+
+    // Pins `content` to the right margin: when the line it's on ends (at
+    // the next newline, or the end of the output), the line is padded with
+    // spaces so that `content`, laid out flat, ends exactly at the line
+    // width. Good for right-aligned badges like `foo ........ OK`. It
+    // doesn't take up room for layout purposes; if the line turns out too
+    // long to fit it, it's written one space after the content instead,
+    // or left out (see `RenderOptions::drop_overflowing_right_suffix`).
+    // Several on the same line are written one after another. Rendered at
+    // a width of `usize::MAX` there's no margin, and it always just
+    // follows the content after a space.
+    pub fn right_align_suffix(content : Self) -> Self {
+        Self::from(RightSuffix { content })
+    }
+
+    // A blank line the user asked for -- e.g. one carried over from the
+    // source a formatter is reformatting -- as opposed to spacing the
+    // layout happens to produce. It always renders as exactly one blank
+    // line (two breaks, at the current indentation). Since it's
+    // intentional, `RenderOptions::max_consecutive_blank_lines` never
+    // takes it out, only the incidental blank lines around it. Like a
+    // `hardline`, its flat width is `usize::MAX`, so no group around it
+    // goes flat.
+    pub fn user_blank_line() -> Self {
+        shared_leaf!(UserBlankLine)
+    }
+
+    // Attach `annotation` to this document. The layout doesn't look at it;
+    // `render_annotated` reports it.
+    pub fn annotate(self, annotation : A) -> Self {
+        Self::from(Annotated {
+            annotation,
+            has_newline : self.get_has_newline(),
+            dist_newline : self.get_dist_newline(),
+            flat_len : self.get_flat_len(),
+            doc : self,
+        })
+    }
+
+    // Defer this document to the end of the line it's on: it's held back
+    // until the next line break (or the end of the output) and written
+    // just before it, even if more of the document comes after it on the
+    // same line. That's where a trailing `// comment` attached to an
+    // expression in the middle of a line has to go. It's laid out flat,
+    // and doesn't take up room for layout purposes. Several on the same
+    // line are written in the order they appear in the document.
+    pub fn line_suffix(self) -> Self {
+        Self::from(LineSuffix { doc : self })
+    }
+
+    // A line break that's always a line break. A group with one in it never
+    // goes flat, however little else it holds, and so neither does any
+    // group around that one; e.g. for a doc comment or a shebang line that
+    // has to be on a line of its own. (A group forced flat with
+    // `RenderOptions::group_overrides` still breaks here.)
+    pub fn hardline() -> Self {
+        shared_leaf!(Hardline)
+    }
+
+    pub fn line() -> Self {
+        Self::newline()
+    }
+
+    pub fn newline() -> Self {
+        shared_leaf!(Newline)
+    }
+
+    pub fn newline_zero() -> Self {
+        shared_leaf!(NewlineZero)
+    }
+
+    // The same leaves as `newline` and `newline_zero`, named for what they
+    // do inside a group: a `softline` is a space while the enclosing group
+    // is flat and a line break once it's broken, and a `softbreak` is
+    // nothing while it's flat and a line break once it's broken. They only
+    // soften inside a `group()` (or `fill` and the like); on their own, at
+    // the top of a document, they're always line breaks.
+    pub fn softline() -> Self {
+        Self::newline()
+    }
+
+    pub fn softbreak() -> Self {
+        Self::newline_zero()
+    }
+
+    // `open`, then the document, then `close`, e.g. for `<...>` or a
+    // multi-character fence. The brackets are whatever documents they're
+    // given; a `&'static str` makes a copy of itself, so for those
+    // `static_text` is the cheaper way in.
+    pub fn surround(self, open : impl Into<Self>, close : impl Into<Self>) -> Self {
+        open.into()
         .concat(self)
-        .concat("}")
+        .concat(close)
+    }
+
+    pub fn surround_paren(self) -> Self {
+        self.surround(shared_leaf!(text "("), shared_leaf!(text ")"))
+    }
+
+    pub fn surround_curly(self) -> Self {
+        self.surround(shared_leaf!(text "{"), shared_leaf!(text "}"))
     }
 
     pub fn surround_square(self) -> Self {
-        Doc::from("[")
-        .concat(self)
-        .concat("]")
+        self.surround(shared_leaf!(text "["), shared_leaf!(text "]"))
     }
 
-    // The stuff with RenderInfo is so we can easily make this
-    // iterative instead of recursive.
+    // A note on sharing: a `Doc` can point at the same `Arc<InnerDoc>` from
+    // several places (by cloning a piece and using it twice, or after
+    // interning), which makes it a DAG rather than a tree. `render` doesn't
+    // try to exploit that; it walks the logical tree top-down and visits a
+    // shared subtree once per occurrence, since whether a group fits
+    // depends on the column it lands at. All of the measurements a visit
+    // needs (`has_newline`, `dist_newline`, `flat_len`) are cached on the
+    // nodes though, so each visit is O(1) and rendering is linear in the
+    // size of the expanded tree -- the same order as the output itself --
+    // no matter how the sharing is arranged.
+    //
+    // Nothing here recurses on the shape of the document, so how deeply
+    // it's nested (a long `Concat` chain leaning either way, or groups
+    // inside groups a million levels down) is limited by memory, never by
+    // the call stack. That goes for building and dropping a document,
+    // rendering it in any of the ways below, measuring it, comparing and
+    // hashing it, `flatten`, `map_text`, `optimize`, `balance`,
+    // `debug_tree` and `DocInterner::intern`. The derived `Debug` output
+    // is the one exception. Closures given to
+    // `column` and the like are up to their callers.
+    //
+    // The invariants for degenerate input are that `Nil` (and empty
+    // text) render to nothing, and that indentation is only ever written
+    // in front of actual content, so no line ends in indentation. At any
+    // width:
+    //
+    //   Doc::nil()                          => ""
+    //   Doc::nil().nest(4)                  => ""
+    //   Doc::text(String::new())            => ""
+    //   Doc::newline()                      => "\n"
+    //   Doc::newline().nest(4)              => "\n"
+    //   Doc::newline_zero().nest(4)         => "\n"
+    //   a, newline, newline, b; nest(2)     => "a\n\n  b"
+    //
+    // `Doc::newline().group()` is " " when it fits (the group goes flat)
+    // and "\n" otherwise, and `Doc::newline_zero().group()` is "" when it
+    // fits. Spaces that are part of the document itself (a " " text, or a
+    // flattened `Newline`) are written as-is, even at the end of a line.
     pub fn render(&self, line_width : usize) -> String {
-        let mut todos = Vec::with_capacity(256);
-        todos.push((self, RenderInfo::new(false, 0, 0, line_width)));
+        let mut s = String::new();
+        self.render_into(line_width, &mut s);
+        s
+    }
+
+    // The document all on one line, as if it were inside a group that
+    // fit: each `Newline` is a space and each `NewlineZero` nothing, so
+    // it's the same as `flatten().render(w)`. No fitting is done at all;
+    // there's no line width, so a `right_align_suffix` just follows the
+    // line's content after a space. Hard line breaks still break.
+    pub fn render_flat(&self) -> String {
+        let options = RenderOptions::new(0);
+        let mut out = Output::new(&options);
+        let mut progress = Progress::with_info(self.clone(), RenderInfo::new(true, 0, 0, 0), &options);
+        Self::resume(&mut progress, &mut out, &options, false, &unmapped, None, None).expect(NO_LIMIT);
+        out.flush_suffixes();
+        out.flush_markup();
+        out.acc
+    }
+
+    // `render`, for output that's going to be written after `start_col`
+    // columns of something else on the same line (a log prefix, the
+    // `let x = ` in front of an expression). Only the first line starts
+    // out that far along, and the offset isn't part of the output; later
+    // lines are indented by their nesting as usual, while an `align` on
+    // the first line lines up with where it'll really be.
+    pub fn render_at(&self, width : usize, start_col : usize) -> String {
+        let options = RenderOptions::new(width);
+        let mut out = Output::new(&options);
+        out.col = start_col;
+        self.render_output(&mut out, &options, false, unmapped, None).expect(NO_LIMIT);
+        out.acc
+    }
+
+    // `render`, appended to `buf`, so that one buffer can be reused for
+    // many renders; clearing it in between is up to the caller. The
+    // layout is the same whatever `buf` already holds: the first line is
+    // laid out as if it started at column 0.
+    pub fn render_into(&self, line_width : usize, buf : &mut String) {
+        self.render_to(line_width, buf).expect("writing to a String can't fail");
+    }
+
+    // Like `render`, but the text is written to `out` as it's produced
+    // instead of being collected into one big `String` first. Fails only
+    // if `out` does, in which case rendering stops there.
+    pub fn render_to<W>(&self, line_width : usize, out : &mut W) -> core::fmt::Result
+    where W : core::fmt::Write {
+        self.render_to_with(&RenderOptions::new(line_width), out)
+    }
+
+    // `render_to` with the knobs of `render_with`, and like it panicking
+    // if `options.max_nodes` runs out. Line breaks at
+    // the end are held back until something follows them, so that
+    // `options.trailing_newline` can still decide what the output ends in.
+    pub fn render_to_with<W>(&self, options : &RenderOptions, out : &mut W) -> core::fmt::Result
+    where W : core::fmt::Write {
+        let mut output = Output::with_sink(options, out);
+        if let Err(e) = self.render_output(&mut output, options, false, unmapped, None) {
+            panic!("{}", e)
+        }
+        output.error.map_or(Ok(()), Err)
+    }
+
+    // Something to hand to `{}` in `format!`, `println!` and the like,
+    // which renders the document straight into the formatter. The width
+    // is the one given in the format string (`{:120}`), and `DEFAULT_WIDTH`
+    // (80) when there isn't one. Documents can't be `Display` themselves,
+    // since anything that's `Display` already converts into a document.
+    pub fn display(&self) -> impl core::fmt::Display + '_ {
+        Displayed(self)
+    }
+
+    // Render with a ribbon of `ribbon_frac` of the line width: a group only
+    // goes flat if, besides fitting on the line, its non-indentation part
+    // fits in the ribbon. See `RenderOptions::ribbon`. `render` is this
+    // with a fraction of 1.0, where the ribbon is the whole line.
+    pub fn render_with_ribbon(&self, line_width : usize, ribbon_frac : f64) -> String {
+        let mut options = RenderOptions::new(line_width);
+        options.ribbon_fraction = ribbon_frac as f32;
+        self.render_with(&options)
+    }
+
+    pub fn render_with(&self, options : &RenderOptions) -> String {
+        match self.try_render_with(options) {
+            Ok(s) => s,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    // Like `render_with`, but running out of `options.max_nodes` is an
+    // error instead of a panic.
+    pub fn try_render_with(&self, options : &RenderOptions) -> Result<String, RenderLimit> {
+        self.render_impl(options, false, unmapped).map(|out| out.acc)
+    }
+
+    // Render as usual, but also record where each `placeholder` region
+    // ended up, so that the result can be given new content later
+    // (`PartialRender::substitute`). The layout is decided using each
+    // placeholder's default content.
+    pub fn render_partial(&self, width : usize) -> PartialRender<A> {
+        let out = self.render_impl(&RenderOptions::new(width), false, unmapped).expect(NO_LIMIT);
+        let placeholders = out.regions.into_iter().filter_map(|(region, range)| match region {
+            Region::Placeholder(id) => Some((id, range)),
+            _ => None,
+        }).collect();
+        PartialRender::new(out.acc, placeholders, self.clone(), width)
+    }
+
+    // Render and cut the result into pages of at most `page_height` lines
+    // (pages don't end in a newline). Pages are filled as far as they'll
+    // go, except that a page ends early rather than split a
+    // `keep_together` block, as long as the block fits on a page of its
+    // own; one that's taller than a page is split anyway. A `page_height`
+    // of 0 is treated as 1.
+    pub fn render_paginated(&self, width : usize, page_height : usize) -> Vec<String> {
+        let page_height = page_height.max(1);
+        let out = self.render_impl(&RenderOptions::new(width), false, unmapped).expect(NO_LIMIT);
+        let line_of = |offset : usize| out.acc[..offset].matches('\n').count();
+        // The blocks as half-open ranges of line numbers.
+        let blocks : Vec<(usize, usize)> = out.regions.iter().filter_map(|(region, range)| match region {
+            Region::KeepTogether if range.end > range.start => Some((line_of(range.start), line_of(range.end - 1) + 1)),
+            _ => None,
+        }).collect();
+
+        let lines : Vec<&str> = out.acc.lines().collect();
+        let mut pages = Vec::new();
+        let mut page_start = 0;
+        while page_start < lines.len() {
+            let mut page_end = (page_start + page_height).min(lines.len());
+            for &(start, end) in blocks.iter() {
+                if start > page_start && start < page_end && end > page_end && end - start <= page_height {
+                    page_end = page_end.min(start);
+                }
+            }
+            pages.push(lines[page_start..page_end].join("\n"));
+            page_start = page_end;
+        }
+        pages
+    }
+
+    // Render, then pass every line through `f` along with its zero-based
+    // index, and join the results back up with newlines. The lines are
+    // exactly what's between the newlines of the output, so blank lines
+    // are passed as "" (and keep their index), and a document ending in a
+    // newline has a final "" line too; an `f` that hands its line back
+    // unchanged gives the same string as `render`.
+    pub fn render_lines_map<F>(&self, width : usize, mut f : F) -> String
+    where F : FnMut(usize, String) -> String {
+        let rendered = self.render(width);
+        let mut acc = String::with_capacity(rendered.len());
+        for (idx, line) in rendered.split('\n').enumerate() {
+            if idx > 0 {
+                acc.push('\n');
+            }
+            acc.push_str(&f(idx, line.to_string()));
+        }
+        acc
+    }
+
+    // Render at `max_width` and draw a box of `border` around the result.
+    // Every line is padded on the right to the width of the longest one,
+    // with a space on either side between it and the border, so the box
+    // always comes out rectangular; a line that overflows `max_width`
+    // just makes the box wider. The box itself doesn't end in a newline.
+    pub fn render_boxed(&self, max_width : usize, border : char) -> String {
+        let rendered = self.render(max_width);
+        let lines : Vec<&str> = rendered.split('\n').collect();
+        let inner = lines.iter().map(|line| display_width(line)).max().unwrap_or(0);
+        let edge : String = core::iter::repeat_n(border, inner + 4).collect();
+
+        let mut acc = String::with_capacity((inner + 5) * (lines.len() + 2));
+        acc.push_str(&edge);
+        for line in lines {
+            acc.push('\n');
+            acc.push(border);
+            acc.push(' ');
+            acc.push_str(line);
+            for _ in display_width(line)..inner {
+                acc.push(' ');
+            }
+            acc.push(' ');
+            acc.push(border);
+        }
+        acc.push('\n');
+        acc.push_str(&edge);
+        acc
+    }
+
+    // Render, but keep the churn against `previous` (e.g. the current
+    // contents of the file being formatted) down to real changes: where a
+    // line of the new output matches the line at the same position in
+    // `previous` except for trailing whitespace and line ending, the
+    // previous line is kept byte for byte, so a file with CRLF endings or
+    // stray trailing spaces isn't rewritten wholesale. Lines that differ
+    // come out as `render` would write them. If only one of the two lines
+    // has a line ending (the last line of one of the texts), the previous
+    // line's content is kept with the new output's ending, so the number
+    // of lines never changes.
+    pub fn render_minimal_diff(&self, width : usize, previous : &str) -> String {
+        let rendered = self.render(width);
+        let mut previous_lines = previous.split_inclusive('\n');
+        let mut acc = String::with_capacity(rendered.len());
+        for line in rendered.split_inclusive('\n') {
+            let (content, ending) = split_line_ending(line);
+            match previous_lines.next() {
+                Some(prev) if split_line_ending(prev).0.trim_end() == content.trim_end() => {
+                    let (prev_content, prev_ending) = split_line_ending(prev);
+                    if prev_ending.is_empty() == ending.is_empty() {
+                        acc.push_str(prev);
+                    } else {
+                        acc.push_str(prev_content);
+                        acc.push_str(ending);
+                    }
+                },
+                _ => acc.push_str(line),
+            }
+        }
+        acc
+    }
+
+    // Like `render`, but avoids the copy when the output is nothing more
+    // than the text of a single atom: a `Text` (or `Nil`), possibly under
+    // `Nest`/`Group` wrappers, renders as itself at any width, so that
+    // case comes back `Cow::Borrowed`. Everything else is `Cow::Owned`.
+    pub fn render_cow(&self, width : usize) -> Cow<'_, str> {
+        let mut doc = self;
+        loop {
+            match doc.as_ref() {
+                Text { s, .. }              => return Cow::Borrowed(s.as_ref()),
+                Nil                         => return Cow::Borrowed(""),
+                Nest { doc : inner, .. }
+                | Group { doc : inner, .. } => doc = inner,
+                _                           => return Cow::Owned(self.render(width)),
+            }
+        }
+    }
+
+    // Render the document, passing each `Text` atom through `f` as it's
+    // written out. Layout is still decided from the widths of the
+    // original atoms, so `f` must preserve display width (recolouring,
+    // redacting to a same-length mask, changing case) or lines will come
+    // out too long/short. If you need to change widths, rebuild the
+    // document instead.
+    pub fn render_map_text<F>(&self, width : usize, f : F) -> String
+    where F : Fn(&str) -> Cow<'_, str> {
+        self.render_impl(&RenderOptions::new(width), false, f).expect(NO_LIMIT).acc
+    }
+
+    // Render to bytes in the given encoding. The layout is the same as
+    // `render`'s; the encoding only changes how the final text is written.
+    pub fn render_bytes(&self, line_width : usize, encoding : Encoding) -> Vec<u8> {
+        encoding.encode(&self.render(line_width))
+    }
 
-        let mut eol = line_width;
+    // Like `render_bytes`, but the text is encoded and written to `w` a
+    // piece at a time as it's produced. That's a lot of small writes, so
+    // `w` should be buffered. Only with the `std` feature.
+    #[cfg(feature = "std")]
+    pub fn render_to_writer<W>(&self, line_width : usize, encoding : Encoding, w : &mut W) -> std::io::Result<()>
+    where W : std::io::Write {
+        w.write_all(encoding.bom())?;
+        let mut sink = IoSink { w, encoding, error : None };
+        match self.render_to(line_width, &mut sink) {
+            Ok(()) => Ok(()),
+            Err(_) => Err(sink.error.take().unwrap_or_else(|| std::io::Error::other("formatting error"))),
+        }
+    }
+
+    // Render at most `width` columns of the flat form of the document
+    // (as if every enclosing group fit and newlines were spaces), and
+    // return the part that didn't make it, so that the tail can be fed into
+    // the next fixed-width cell.
+    //
+    // The rules at the edge of a cell are:
+    // + A `Newline` that lands exactly at the end of the cell is
+    //   swallowed instead of showing up as a leading space in the tail.
+    // + A `Text` that doesn't fit is split mid-atom at the last character
+    //   boundary that fits; the rest of it starts the tail. If the cell is
+    //   still empty we always take at least one character, so a loop over
+    //   `render_prefix` makes progress even for a `width` that's narrower
+    //   than a single character.
+    // + A hard break (`hardline`, `user_blank_line`) ends the cell, and is
+    //   used up by it: the tail starts after it, so it never leaves a
+    //   loop over `render_prefix` stuck in front of it.
+    // + The tail is `None` when nothing with any flat width is left.
+    //
+    // The tail is rebuilt from the pieces that were still waiting to be
+    // rendered, so `Nest`/`Group` nodes that were only partly consumed are
+    // not carried over; that doesn't matter for flat content.
+    pub fn render_prefix(&self, width : usize) -> (String, Option<Self>) {
+        let mut todos = vec![self.clone()];
         let mut acc = String::new();
+        let mut col = 0;
+        let mut tabs = Map::new();
+
+        while let Some(doc) = todos.pop() {
+            match doc.as_ref() {
+                Nil | NewlineZero => continue,
+                Newline if col == width => continue,
+                Newline if col < width => {
+                    acc.push(' ');
+                    col += 1;
+                },
+                Newline | UserBlankLine | Hardline => break,
+                Text { s, len } if col + len <= width => {
+                    acc.push_str(s);
+                    col += len;
+                },
+                Text { s, .. } => {
+                    let room = width.saturating_sub(col);
+                    let mut used = 0;
+                    let mut cut = 0;
+                    for (idx, c) in s.char_indices() {
+                        used += c.width().unwrap_or(0);
+                        if used > room {
+                            break
+                        }
+                        cut = idx + c.len_utf8();
+                    }
+                    if cut == 0 && acc.is_empty() {
+                        cut = s.chars().next().map_or(0, char::len_utf8);
+                    }
+                    acc.push_str(&s[..cut]);
+                    if cut < s.len() {
+                        todos.push(Self::text(s[cut..].to_string()));
+                    }
+                    break
+                },
+                Concat { lhs, rhs, .. } => {
+                    todos.push(rhs.clone());
+                    todos.push(lhs.clone());
+                },
+                Nest { doc : inner, .. }
+                | Group { doc : inner, .. }
+                | Placeholder { doc : inner, .. }
+                | KeepTogether { doc : inner, .. }
+                | WithWidth { doc : inner, .. }
+                | Continued { doc : inner, .. }
+                | Annotated { doc : inner, .. }
+                | Fill { doc : inner, .. }
+                | Align { doc : inner, .. }
+                | Fold { body : inner, .. } => todos.push(inner.clone()),
+                Alt { options, .. } => todos.push(options[0].clone()),
+                Padded { width : pad, doc : inner, .. } => {
+                    todos.push(Self::spaces(pad.saturating_sub(inner.get_flat_len())));
+                    todos.push(inner.clone());
+                },
+                RightSuffix { .. } | LineSuffix { .. } => continue,
+                Column { f } => todos.push((f.0)(col)),
+                Nesting { f } => todos.push((f.0)(0)),
+                LineCount { header, body, .. } => {
+                    todos.push(body.clone());
+                    todos.push((header.0)(1));
+                },
+                TabStop { width : tab } => {
+                    let target = next_tab_stop(col, *tab).min(width);
+                    for _ in col..target {
+                        acc.push(' ');
+                    }
+                    col = col.max(target);
+                },
+                SetTab { name } => { tabs.insert(*name, col); },
+                TabTo { name } => {
+                    let target = tabs.get(name).copied().unwrap_or(0).min(width);
+                    for _ in col..target {
+                        acc.push(' ');
+                    }
+                    col = col.max(target);
+                },
+            }
+        }
+
+        todos.reverse();
+        let tail = sep(&todos);
+        if tail.get_flat_len() == 0 {
+            (acc, None)
+        } else {
+            (acc, Some(tail))
+        }
+    }
+
+    // How many lines `render(width)` comes out as (its newlines, plus
+    // one), worked out with the same layout decisions but without keeping
+    // the text. Together with `render_width` that's the size of the box
+    // the output needs.
+    pub fn count_lines(&self, width : usize) -> usize {
+        let options = RenderOptions::new(width);
+        let mut out = Output::with_sink(&options, Discard);
+        self.render_output(&mut out, &options, false, unmapped, None).expect(NO_LIMIT);
+        out.line_no + 1
+    }
+
+    // The narrowest width at which the document takes up at most
+    // `max_lines` lines, or `None` if it takes more than that however wide
+    // the line is (because of newlines that never go flat). Found by a
+    // binary search, which relies on a wider line never producing more
+    // lines; past the widest line of the document laid out with no limit
+    // on the width (at most its flat width) nothing changes anymore, so
+    // that's as far as it looks.
+    pub fn min_width_for_lines(&self, max_lines : usize) -> Option<usize> {
+        let mut hi = self.get_flat_len().min(self.render_width(usize::MAX));
+        if self.count_lines(hi) > max_lines {
+            return None
+        }
+        let mut lo = 0;
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            if self.count_lines(mid) <= max_lines {
+                hi = mid;
+            } else {
+                lo = mid + 1;
+            }
+        }
+        Some(lo)
+    }
+
+    // Render as usual, and also report how every group was laid out and
+    // why, in the order they were reached. Meant for snapshot tests that
+    // want to catch a layout changing, not just the output.
+    pub fn render_trace(&self, width : usize) -> (String, Vec<GroupDecision>) {
+        let options = RenderOptions::new(width);
+        let mut out = Output::new(&options);
+        out.trace = Some(Vec::new());
+        self.render_output(&mut out, &options, false, unmapped, None).expect(NO_LIMIT);
+        let trace = out.trace.take().unwrap_or_default();
+        (out.acc, trace)
+    }
+
+    // Render with `options`, and also report where every `foldable` region
+    // ended up, in the order they were entered, collapsed ones included.
+    // As with `render_with_spans`, a range starts at the first thing the
+    // fold wrote, and one that's nested in another lies inside it.
+    pub fn render_detailed(&self, options : &RenderOptions) -> (String, Vec<FoldMarker>) {
+        let mut out = Output::new(options);
+        if let Err(e) = self.render_output(&mut out, options, false, unmapped, None) {
+            panic!("{}", e)
+        }
+        let line_of = |offset : usize| out.acc[..offset].matches('\n').count();
+        let folds = out.regions.iter().filter_map(|(region, range)| match region {
+            Region::Fold(id, collapsed) => Some(FoldMarker {
+                id : *id,
+                range : range.clone(),
+                lines : line_of(range.start)..line_of(range.end.saturating_sub(1).max(range.start)) + 1,
+                collapsed : *collapsed,
+            }),
+            _ => None,
+        }).collect();
+        (out.acc, folds)
+    }
+
+    // Render as usual, and also report every line that's wider than
+    // `width`, in order. Such lines come from text that can't be broken
+    // (a long atom, a group with no break in it), so for a document that's
+    // supposed to fit, an empty list is the thing to check for.
+    pub fn render_checked(&self, width : usize) -> (String, Vec<Overflow>) {
+        let options = RenderOptions::new(width);
+        let mut out = Output::new(&options);
+        out.overflows = Some((width, Vec::new()));
+        self.render_output(&mut out, &options, false, unmapped, None).expect(NO_LIMIT);
+        out.end_line();
+        let (_, overflows) = out.overflows.take().unwrap_or_default();
+        (out.acc, overflows)
+    }
+
+    // The width in display columns of the widest line `render(line_width)`
+    // comes out with, worked out without keeping the text.
+    pub fn render_width(&self, line_width : usize) -> usize {
+        let options = RenderOptions::new(line_width);
+        let mut out = Output::with_sink(&options, Discard);
+        self.render_output(&mut out, &options, false, unmapped, None).expect(NO_LIMIT);
+        out.end_line();
+        out.widest
+    }
+
+    // Render as usual, calling `push` where each annotated part of the
+    // document starts and `pop` where it ends, and writing whatever they
+    // return into the output right there; e.g. an HTML tag or an ANSI
+    // escape. What they return doesn't count toward the width of the line.
+    // Where an annotated part starts a line, its markup goes after the
+    // indentation rather than before it.
+    pub fn render_annotated<P, Q>(&self, width : usize, push : P, pop : Q) -> String
+    where P : FnMut(&A) -> String, Q : FnMut(&A) -> String {
+        self.render_annotated_indents(width, push, pop).0
+    }
+
+    // `render_annotated`, along with the byte extent of the indentation
+    // the renderer put at the start of each line (not counting lines that
+    // got none), so the ANSI renderers can tell it from text that happens
+    // to start with spaces.
+    pub(crate) fn render_annotated_indents<P, Q>(&self, width : usize, mut push : P, mut pop : Q) -> (String, Vec<core::ops::Range<usize>>)
+    where P : FnMut(&A) -> String, Q : FnMut(&A) -> String {
+        let options = RenderOptions::new(width);
+        let mut out = Output::new(&options);
+        out.indents = Some(Vec::new());
+        let mut hooks = AnnotationHooks { push : &mut push, pop : &mut pop, record_spans : false };
+        self.render_output(&mut out, &options, false, unmapped, Some(&mut hooks)).expect(NO_LIMIT);
+        (out.acc, out.indents.unwrap_or_default())
+    }
+
+    // Render as usual, and also say where in the output each annotated
+    // part of the document ended up: a byte range of the rendered text for
+    // every `Annotated` node rendered, with its annotation, in the order
+    // they were entered, so an enclosing part comes before the parts
+    // nested in it and its range covers theirs. A range starts at the
+    // first thing the part wrote, so indentation in front of it isn't
+    // included, and is empty if it wrote nothing.
+    pub fn render_with_spans(&self, width : usize) -> (String, Vec<(core::ops::Range<usize>, A)>)
+    where A : Clone {
+        let (text, spans, _) = self.render_spans_indents(width);
+        (text, spans)
+    }
+
+    // `render_with_spans`, along with the extent of each line's
+    // indentation, as for `render_annotated_indents`.
+    pub(crate) fn render_spans_indents(&self, width : usize) -> (String, Spans<A>, Vec<core::ops::Range<usize>>)
+    where A : Clone {
+        let options = RenderOptions::new(width);
+        let mut out = Output::new(&options);
+        out.indents = Some(Vec::new());
+        let entered = RefCell::new(Vec::new());
+        let mut push = |annotation : &A| {
+            entered.borrow_mut().push(annotation.clone());
+            String::new()
+        };
+        let mut pop = |_ : &A| String::new();
+        let mut hooks = AnnotationHooks { push : &mut push, pop : &mut pop, record_spans : true };
+        self.render_output(&mut out, &options, false, unmapped, Some(&mut hooks)).expect(NO_LIMIT);
+        let ranges = out.regions.into_iter().filter_map(|(region, range)| match region {
+            Region::Annotation => Some(range),
+            _ => None,
+        });
+        let spans = ranges.zip(entered.into_inner()).collect();
+        (out.acc, spans, out.indents.unwrap_or_default())
+    }
+
+    // Render one line at a time, as the iterator is advanced, so the whole
+    // output never has to be in memory at once. The lines come with their
+    // indentation but without their newlines, and are exactly what
+    // `render(width).split('\n')` would give.
+    pub fn render_lines(&self, width : usize) -> impl Iterator<Item = String> {
+        let options = RenderOptions::new(width);
+        RenderLines {
+            progress : Progress::new(self.clone(), &options),
+            out : Output::new(&options),
+            options,
+            finished : false,
+        }
+    }
+
+    // Render at most `max_lines` lines. If there's more to the document
+    // than that, rendering stops there and "…" goes at the end of the
+    // last line to say so; a document that fits comes out in full, the
+    // same as `render`.
+    pub fn render_truncated(&self, width : usize, max_lines : usize) -> String {
+        self.render_truncated_with(width, max_lines, "…")
+    }
+
+    // `render_truncated`, with `marker` to mark the cut instead of "…".
+    pub fn render_truncated_with(&self, width : usize, max_lines : usize, marker : &str) -> String {
+        let options = RenderOptions::new(width);
+        let mut progress = Progress::new(self.clone(), &options);
+        let mut out = Output::new(&options);
+        Self::resume(&mut progress, &mut out, &options, false, &unmapped, None, Some(max_lines)).expect(NO_LIMIT);
+        if progress.todos.is_empty() {
+            out.flush_suffixes();
+            out.flush_markup();
+        } else {
+            // Stopped just past the newline that ended line `max_lines`.
+            if out.acc.ends_with('\n') {
+                out.acc.pop();
+            }
+            out.acc.push_str(marker);
+        }
+        out.acc
+    }
+
+    // Render the whole document as one soft-wrapped paragraph. Every
+    // break, hard ones and blank lines included, is treated as a wrap point
+    // instead of a mandatory one, regardless of grouping: it becomes a space
+    // (or nothing, for `NewlineZero`) if the text up to the next break still
+    // fits in `width`, and a real newline otherwise. Runs of consecutive
+    // breaks collapse into one, and breaks at the very start or end of
+    // the output are dropped.
+    pub fn render_soft_only(&self, width : usize) -> String {
+        self.render_impl(&RenderOptions::new(width), true, unmapped).expect(NO_LIMIT).acc
+    }
+
+    // The stuff with RenderInfo is so we can easily make this
+    // iterative instead of recursive.
+    fn render_impl<F>(&self, options : &RenderOptions, soft_only : bool, map_text : F) -> Result<Output, RenderLimit>
+    where F : Fn(&str) -> Cow<'_, str> {
+        let mut out = Output::new(options);
+        self.render_output(&mut out, options, soft_only, map_text, None)?;
+        Ok(out)
+    }
+
+    fn render_output<W, F>(&self,
+                           out : &mut Output<W>,
+                           options : &RenderOptions,
+                           soft_only : bool,
+                           map_text : F,
+                           hooks : Option<&mut AnnotationHooks<'_, A>>) -> Result<(), RenderLimit>
+    where W : core::fmt::Write, F : Fn(&str) -> Cow<'_, str> {
+        let mut progress = Progress::new(self.clone(), options);
+        Self::resume(&mut progress, out, options, soft_only, &map_text, hooks, None)?;
+        out.flush_suffixes();
+        out.flush_markup();
+        out.finish_line_endings();
+        Ok(())
+    }
+
+    // Carry on with the render `progress` is partway through, until it's
+    // done or, if `stop_at_line` is given, until the output has got to
+    // the start of that line. What's held back for the end of the last
+    // line isn't written out; that's up to the caller once it's done.
+    fn resume<W, F>(progress : &mut Progress<A>,
+                    out : &mut Output<W>,
+                    options : &RenderOptions,
+                    soft_only : bool,
+                    map_text : &F,
+                    mut hooks : Option<&mut AnnotationHooks<'_, A>>,
+                    stop_at_line : Option<usize>) -> Result<(), RenderLimit>
+    where W : core::fmt::Write, F : Fn(&str) -> Cow<'_, str> {
+        let Progress { todos, pending_break, at_start, nodes_left, probed } = progress;
 
-        while let Some((doc, info)) = todos.pop() {
+        while let Some(step) = todos.pop() {
+            if out.error.is_some() {
+                break
+            }
+            if stop_at_line.is_some_and(|line| out.line_no >= line) {
+                todos.push(step);
+                break
+            }
+            let (doc, info) = match step {
+                Step::Render(doc, info) => (doc, info),
+                Step::EndRegion(idx) => {
+                    out.end_region(idx);
+                    continue
+                },
+                Step::EndAnnotation(node) => {
+                    if let (Some(h), Annotated { annotation, .. }) = (hooks.as_mut(), node.as_ref()) {
+                        let markup = (h.pop)(annotation);
+                        out.markup(&markup);
+                    }
+                    continue
+                },
+                Step::EndPadded { start, width, or_break, info } => {
+                    let target = start.saturating_add(width);
+                    let col = out.column();
+                    if col < target {
+                        out.resolve_soft_break(pending_break, (target - col).saturating_add(info.dist_next_newline), info);
+                        *at_start = false;
+                        for _ in out.column()..target {
+                            out.push_str(" ");
+                        }
+                        continue
+                    } else if !or_break {
+                        continue
+                    }
+                    let break_info = RenderInfo {
+                        nest : info.nest.saturating_add(width),
+                        ..info
+                    };
+                    (Self::newline_zero(), break_info)
+                },
+            };
+            if let Some(n) = nodes_left.as_mut() {
+                match n.checked_sub(1) {
+                    Some(rest) => *n = rest,
+                    None => return Err(RenderLimit { max_nodes : options.max_nodes.unwrap_or(0) }),
+                }
+            }
             match doc.as_ref() {
                 Nil => continue,
-                Newline if info.flatmode => { acc.push_str(" "); },
+                Newline | NewlineZero | Hardline | UserBlankLine if soft_only => {
+                    if *at_start {
+                        continue
+                    }
+                    let spaced = !matches!(doc.as_ref(), NewlineZero) || pending_break.is_some_and(|(sp, _)| sp);
+                    *pending_break = Some((spaced, info.nest));
+                },
+                UserBlankLine => {
+                    *pending_break = None;
+                    out.newline(info.nest);
+                    out.newline(info.nest);
+                },
+                Hardline => {
+                    *pending_break = None;
+                    out.break_line(info.nest);
+                },
+                Newline if info.flatmode => out.push_str(" "),
                 NewlineZero if info.flatmode => continue,
+                Newline if info.fillmode && out.column().saturating_add(1).saturating_add(info.dist_next_newline) <= info.line_width => out.push_str(" "),
+                NewlineZero if info.fillmode && out.column().saturating_add(info.dist_next_newline) <= info.line_width => continue,
                 Newline | NewlineZero => {
                     assert!(!info.flatmode);
-                    acc.push_str("\n");
-                    eol = (acc.len() + info.line_width);
-                    for _ in 0..info.nest {
-                        acc.push(' ');
+                    if info.continued {
+                        out.push_str(CONTINUATION);
                     }
+                    out.break_line(info.nest);
                 }
-                Text { s, .. } => acc.push_str(s.as_str()),
+                Text { s, len } => {
+                    out.resolve_soft_break(pending_break, len.saturating_add(info.dist_next_newline), info);
+                    *at_start = false;
+                    let text = map_text(s);
+                    match options.max_token_width {
+                        Some(max) if display_width(&text) > max => out.push_str(&truncate_tokens(&text, max)),
+                        _                                       => out.push_str(&text),
+                    }
+                },
+                TabStop { width } => {
+                    out.resolve_soft_break(pending_break, info.dist_next_newline, info);
+                    *at_start = false;
+                    let col = out.column();
+                    for _ in col..next_tab_stop(col, *width) {
+                        out.push_str(" ");
+                    }
+                },
+                SetTab { name } => {
+                    out.resolve_soft_break(pending_break, info.dist_next_newline, info);
+                    let col = out.column();
+                    out.tabs.insert(*name, col);
+                },
+                TabTo { name } => {
+                    out.resolve_soft_break(pending_break, info.dist_next_newline, info);
+                    *at_start = false;
+                    let col = out.column();
+                    let target = out.tabs.get(name).copied().unwrap_or(0);
+                    for _ in col..target {
+                        out.push_str(" ");
+                    }
+                },
                 Concat { lhs, rhs, .. } => {
                     let lhs_dist_next_newline = if rhs.get_has_newline() {
                         rhs.get_dist_newline()
                     } else {
-                        rhs.get_dist_newline() + info.dist_next_newline
+                        rhs.get_dist_newline().saturating_add(info.dist_next_newline)
                     };
 
-                    let lhs_info = RenderInfo::new(info.flatmode, 
-                                                   info.nest,
-                                                   lhs_dist_next_newline,
-                                                   info.line_width);
-                    todos.push((rhs, info));
-                    todos.push((lhs, lhs_info));
+                    let lhs_info = RenderInfo {
+                        dist_next_newline : lhs_dist_next_newline,
+                        ..info
+                    };
+                    todos.push(Step::Render(rhs.clone(), info));
+                    todos.push(Step::Render(lhs.clone(), lhs_info));
                 },
                 Nest { nest : spaces, doc : inner, .. } => {
-                    let inner_info = RenderInfo::new(info.flatmode,
-                                                     info.nest + spaces,
-                                                     info.dist_next_newline,
-                                                     info.line_width);
-                    todos.push((inner, inner_info));
+                    let inner_info = RenderInfo {
+                        nest : info.nest.saturating_add_signed(spaces.saturating_mul(options.indent_width() as isize)),
+                        ..info
+                    };
+                    todos.push(Step::Render(inner.clone(), inner_info));
+                },
+                Group { doc : inner, name, first_line, .. } => {
+                    let mode = name.and_then(|n| options.group_overrides.get(n)).copied().unwrap_or(GroupMode::Auto);
+                    let (flat_bool, fill_bool) = if soft_only {
+                        (false, false)
+                    } else {
+                        match mode {
+                            GroupMode::ForceBreak => (false, false),
+                            GroupMode::ForceFlat  => (true, false),
+                            GroupMode::Auto       => {
+                                let trailing = if options.trailing_content_forces_break { info.dist_next_newline } else { 0 };
+                                let ribbon = options.ribbon_width(info.line_width);
+                                let indent = info.nest.min(out.column());
+                                let fits_line = |len : usize| {
+                                    out.column().saturating_add(len) <= info.line_width
+                                    && (out.column() - indent).saturating_add(len) <= ribbon
+                                };
+                                let fits = (info.flatmode || fits_line(inner.get_flat_len().saturating_add(trailing)));
+                                let first_line_fits = *first_line
+                                                      && inner.get_has_newline()
+                                                      && fits_line(inner.get_dist_newline());
+                                (fits, !fits && first_line_fits)
+                            },
+                        }
+                    };
+                    let column = out.column();
+                    if let Some(trace) = out.trace.as_mut() {
+                        trace.push(GroupDecision {
+                            name : *name,
+                            column,
+                            flat_width : inner.get_flat_len(),
+                            available : info.line_width.saturating_sub(column),
+                            broke : !flat_bool,
+                        });
+                    }
+                    let inner_info = RenderInfo {
+                        flatmode : flat_bool,
+                        fillmode : fill_bool,
+                        ..info
+                    };
+                    todos.push(Step::Render(inner.clone(), inner_info));
+                },
+                Placeholder { id, doc : inner, .. } => {
+                    // Write out any indentation that's owed, so the region
+                    // starts where its first character will actually be.
+                    out.flush_indent();
+                    let start = out.written;
+                    out.regions.push((Region::Placeholder(*id), start..start));
+                    todos.push(Step::EndRegion(out.regions.len() - 1));
+                    todos.push(Step::Render(inner.clone(), info));
+                },
+                WithWidth { width, doc : inner, .. } => {
+                    let inner_info = RenderInfo {
+                        line_width : *width,
+                        ..info
+                    };
+                    todos.push(Step::Render(inner.clone(), inner_info));
+                },
+                KeepTogether { doc : inner, .. } => {
+                    let start = out.written;
+                    out.regions.push((Region::KeepTogether, start..start));
+                    todos.push(Step::EndRegion(out.regions.len() - 1));
+                    todos.push(Step::Render(inner.clone(), info));
+                },
+                Alt { options : alternatives, .. } => {
+                    let chosen = if soft_only || info.flatmode {
+                        &alternatives[0]
+                    } else {
+                        let trailing = if options.trailing_content_forces_break { info.dist_next_newline } else { 0 };
+                        let ribbon = options.ribbon_width(info.line_width);
+                        let indent = info.nest.min(out.column());
+                        let fits_line = |len : usize| {
+                            out.column().saturating_add(len) <= info.line_width
+                            && (out.column() - indent).saturating_add(len) <= ribbon
+                        };
+                        alternatives.iter().find(|alt| if alt.get_has_newline() {
+                            fits_line(alt.get_dist_newline())
+                        } else {
+                            fits_line(alt.get_flat_len().saturating_add(trailing))
+                        }).unwrap_or(&alternatives[alternatives.len() - 1])
+                    };
+                    todos.push(Step::Render(chosen.clone(), info));
+                },
+                Column { f } => todos.push(Step::Render((f.0)(out.column()), info)),
+                Nesting { f } => todos.push(Step::Render((f.0)(info.nest), info)),
+                Align { extra, doc : inner, .. } => {
+                    let inner_info = RenderInfo {
+                        nest : out.column() + extra,
+                        ..info
+                    };
+                    todos.push(Step::Render(inner.clone(), inner_info));
+                },
+                Padded { width, or_break, doc : inner, .. } => {
+                    out.resolve_soft_break(pending_break, info.dist_next_newline, info);
+                    todos.push(Step::EndPadded { start : out.column(), width : *width, or_break : *or_break, info });
+                    todos.push(Step::Render(inner.clone(), info));
+                },
+                Fill { doc : inner, .. } => {
+                    let trailing = if options.trailing_content_forces_break { info.dist_next_newline } else { 0 };
+                    let fits = soft_only || info.flatmode || out.column().saturating_add(inner.get_flat_len()).saturating_add(trailing) <= info.line_width;
+                    let segments = match options.line_break {
+                        LineBreak::Optimal if !fits => inner.fill_segments(),
+                        _ => None,
+                    };
+                    if let Some(segments) = segments {
+                        let widths = segments.iter().map(|seg| seg.iter().map(Self::get_flat_len).sum()).collect::<Vec<usize>>();
+                        let breaks = optimal_breaks(&widths, out.column(), info.nest, info.line_width, trailing);
+                        let broken_info = RenderInfo {
+                            flatmode : false,
+                            fillmode : false,
+                            ..info
+                        };
+                        // Everything's on the stack in reverse. What follows
+                        // a segment up to the next newline is the rest of its
+                        // line, or what follows the fill for the last line.
+                        let mut dist = info.dist_next_newline;
+                        for (i, seg) in segments.iter().enumerate().rev() {
+                            for piece in seg.iter().rev() {
+                                todos.push(Step::Render(piece.clone(), RenderInfo { dist_next_newline : dist, ..broken_info }));
+                                dist = dist.saturating_add(piece.get_flat_len());
+                            }
+                            if i > 0 {
+                                if breaks[i - 1] {
+                                    todos.push(Step::Render(Self::newline(), broken_info));
+                                    dist = 0;
+                                } else {
+                                    todos.push(Step::Render(Self::space(), RenderInfo { dist_next_newline : dist, ..broken_info }));
+                                    dist = dist.saturating_add(1);
+                                }
+                            }
+                        }
+                        continue
+                    }
+                    let inner_info = RenderInfo {
+                        flatmode : fits && !soft_only,
+                        fillmode : !fits,
+                        ..info
+                    };
+                    todos.push(Step::Render(inner.clone(), inner_info));
+                },
+                Annotated { annotation, doc : inner, .. } => {
+                    if let Some(h) = hooks.as_mut() {
+                        let markup = (h.push)(annotation);
+                        out.markup(&markup);
+                        todos.push(Step::EndAnnotation(doc.clone()));
+                        if h.record_spans {
+                            let idx = out.start_text_region(Region::Annotation);
+                            todos.push(Step::EndRegion(idx));
+                        }
+                    }
+                    todos.push(Step::Render(inner.clone(), info));
+                },
+                Fold { id, summary, body, .. } => {
+                    let collapsed = options.collapsed.contains(id);
+                    let idx = out.start_text_region(Region::Fold(*id, collapsed));
+                    todos.push(Step::EndRegion(idx));
+                    if collapsed {
+                        let summary_info = RenderInfo {
+                            flatmode : true,
+                            ..info
+                        };
+                        todos.push(Step::Render(summary.clone(), summary_info));
+                    } else {
+                        todos.push(Step::Render(body.clone(), info));
+                    }
+                },
+                LineSuffix { doc : inner } => {
+                    let text = inner.render_prefix(inner.get_flat_len()).0;
+                    out.line_suffix.push_str(&text);
+                },
+                RightSuffix { content } => {
+                    let text = content.render_prefix(content.get_flat_len()).0;
+                    match out.right_suffix.as_mut() {
+                        Some((pending, _)) => pending.push_str(&text),
+                        None => out.right_suffix = Some((text, info.line_width)),
+                    }
                 },
-                Group { doc : inner, .. } => {
-                    let flat_bool = (info.flatmode || (acc.len() + inner.get_flat_len() + info.dist_next_newline <= eol));
-                    let inner_info = RenderInfo::new(flat_bool, info.nest, info.dist_next_newline, info.line_width);
-                    todos.push((inner, inner_info));
+                Continued { doc : inner, .. } => {
+                    let inner_info = RenderInfo {
+                        continued : true,
+                        line_width : info.line_width.saturating_sub(CONTINUATION.len()),
+                        ..info
+                    };
+                    todos.push(Step::Render(inner.clone(), inner_info));
+                },
+                LineCount { header, body, .. } => {
+                    let key = (Arc::as_ptr(&doc.0) as usize, out.column(), info.nest, info.line_width, info.dist_next_newline);
+                    let count = if info.flatmode {
+                        1
+                    } else if let Some((_, count)) = probed.get(&key) {
+                        *count
+                    } else {
+                        // Lay `body` out on its own, padded so it starts at
+                        // the current column and is followed by as much as
+                        // will follow it here. `info.nest` is in columns
+                        // already, so it's set with `hang` rather than `nest`.
+                        let probe = Self::spaces(out.column())
+                                    .concat(body.clone())
+                                    .concat(Self::spaces(info.dist_next_newline))
+                                    .hang(info.nest);
+                        let probe_options = RenderOptions {
+                            line_width : info.line_width,
+                            indent_fn : None,
+                            ..options.clone()
+                        };
+                        // The probe's steps come out of the same budget as
+                        // the rest of the render, and it shares the counts
+                        // found so far, so a `with_line_count` nested in
+                        // `body` is probed once rather than once per level.
+                        let mut probe_out = Output::new(&probe_options);
+                        let mut probe_progress = Progress::new(probe, &probe_options);
+                        probe_progress.nodes_left = *nodes_left;
+                        probe_progress.probed = core::mem::take(probed);
+                        let res = Self::resume(&mut probe_progress, &mut probe_out, &probe_options, soft_only, &unmapped, None, None);
+                        *nodes_left = probe_progress.nodes_left;
+                        *probed = core::mem::take(&mut probe_progress.probed);
+                        res?;
+                        probe_out.flush_suffixes();
+                        probe_out.finish_line_endings();
+                        let count = probe_out.acc.matches('\n').count() + 1;
+                        // The node is kept alongside its count so its address
+                        // can't be reused by another one while the key exists.
+                        probed.insert(key, (doc.clone(), count));
+                        count
+                    };
+                    let header_info = RenderInfo {
+                        dist_next_newline : if body.get_has_newline() {
+                            body.get_dist_newline()
+                        } else {
+                            body.get_dist_newline().saturating_add(info.dist_next_newline)
+                        },
+                        ..info
+                    };
+                    todos.push(Step::Render(body.clone(), info));
+                    todos.push(Step::Render((header.0)(count), header_info));
                 },
            }
         }
-        acc
+        Ok(())
+    }
+ 
+
+    pub fn as_parenable_max(self) -> Parenable<A> {
+        Parenable::new_max(self)
+    }
+
+    pub fn as_parenable(self, priority : usize) -> Parenable<A> {
+        Parenable::new(self, priority)
+    }
+
+}
+
+// How far a render has got: what's left to do, plus the state of the
+// soft-only mode's held breaks and of the node budget.
+struct Progress<A : 'static> {
+    todos : Vec<Step<A>>,
+    // In `soft_only` mode, breaks aren't emitted when they're reached;
+    // they're held here until the next piece of text shows up, so that
+    // a run of breaks collapses into one and the wrap decision can take
+    // the width of that text into account. If any of the held breaks
+    // was a `Newline`, the flat form is a space, otherwise it's empty.
+    pending_break : Option<(bool, usize)>,
+    at_start : bool,
+    nodes_left : Option<usize>,
+    // What each `with_line_count` probe has found so far.
+    probed : Map<ProbeKey, (AnnotatedDoc<A>, usize)>,
+}
+
+// What `render_with_spans` reports: each annotated part's byte range in
+// the output, with its annotation.
+type Spans<A> = Vec<(core::ops::Range<usize>, A)>;
+
+// A `LineCount` node's address, and where it was laid out: the column,
+// the nest, the line width and the distance to the next newline after it.
+type ProbeKey = (usize, usize, usize, usize, usize);
+
+impl<A> Progress<A> {
+    fn new(doc : AnnotatedDoc<A>, options : &RenderOptions) -> Self {
+        Self::with_info(doc, RenderInfo::new(false, 0, 0, options.line_width), options)
+    }
+
+    // A render of `doc` that starts out in the state `info` gives.
+    fn with_info(doc : AnnotatedDoc<A>, info : RenderInfo, options : &RenderOptions) -> Self {
+        let mut todos = Vec::with_capacity(256);
+        todos.push(Step::Render(doc, info));
+        Progress {
+            todos,
+            pending_break : None,
+            at_start : true,
+            nodes_left : options.max_nodes,
+            probed : Map::new(),
+        }
+    }
+}
+
+// The iterator `Doc::text_leaves` returns: a depth-first walk over a stack,
+// so long chains of `Concat` don't use up the call stack.
+struct TextLeaves<'d, A : 'static> {
+    todo : Vec<&'d AnnotatedDoc<A>>,
+}
+
+impl<'d, A : 'static> Iterator for TextLeaves<'d, A> {
+    type Item = &'d str;
+
+    fn next(&mut self) -> Option<&'d str> {
+        while let Some(doc) = self.todo.pop() {
+            match doc.as_ref() {
+                Text { s, .. } => return Some(s.as_ref()),
+                Concat { lhs, rhs, .. } => {
+                    self.todo.push(rhs);
+                    self.todo.push(lhs);
+                },
+                Nest { doc : inner, .. }
+                | Group { doc : inner, .. }
+                | Placeholder { doc : inner, .. }
+                | KeepTogether { doc : inner, .. }
+                | WithWidth { doc : inner, .. }
+                | Continued { doc : inner, .. }
+                | Annotated { doc : inner, .. }
+                | Fill { doc : inner, .. }
+                | Padded { doc : inner, .. }
+                | Align { doc : inner, .. }
+                | LineSuffix { doc : inner }
+                | RightSuffix { content : inner }
+                | Fold { body : inner, .. }
+                | LineCount { body : inner, .. } => self.todo.push(inner),
+                Alt { options, .. } => self.todo.push(&options[0]),
+                Nil
+                | Newline
+                | NewlineZero
+                | UserBlankLine
+                | Hardline
+                | TabStop { .. }
+                | SetTab { .. }
+                | TabTo { .. }
+                | Column { .. }
+                | Nesting { .. } => continue,
+            }
+        }
+        None
+    }
+}
+
+// What `Doc::display` returns.
+struct Displayed<'d, A : 'static>(&'d AnnotatedDoc<A>);
+
+impl<A : 'static> core::fmt::Display for Displayed<'_, A> {
+    fn fmt(&self, f : &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let width = f.width().unwrap_or(DEFAULT_WIDTH);
+        self.0.render_to(width, f)
+    }
+}
+
+// The iterator `Doc::render_lines` returns. `out` only ever holds the
+// part of the output that hasn't been handed out yet.
+struct RenderLines<A : 'static> {
+    progress : Progress<A>,
+    out : Output,
+    options : RenderOptions,
+    finished : bool,
+}
+
+impl<A : 'static> Iterator for RenderLines<A> {
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        loop {
+            if let Some(end) = self.out.acc.find('\n') {
+                let line = self.out.acc[..end].to_string();
+                self.out.acc.drain(..=end);
+                return Some(line)
+            }
+            if self.finished {
+                return None
+            }
+            if self.progress.todos.is_empty() {
+                self.out.flush_suffixes();
+                self.out.flush_markup();
+                self.finished = true;
+                return Some(core::mem::take(&mut self.out.acc))
+            }
+            let next_line = self.out.line_no + 1;
+            AnnotatedDoc::resume(&mut self.progress, &mut self.out, &self.options, false, &unmapped, None, Some(next_line))
+                .expect(NO_LIMIT);
+        }
+    }
+}
+
+// An entry on `render_impl`'s work stack: either a document still to be
+// rendered, or a marker for the point where a region that's being tracked
+// ends.
+enum Step<A : 'static> {
+    Render(AnnotatedDoc<A>, RenderInfo),
+    EndRegion(usize),
+    // The end of an `Annotated` node, which is carried along so its
+    // annotation can be handed to `render_annotated`'s hook.
+    EndAnnotation(AnnotatedDoc<A>),
+    // The end of a `Padded` node that started at column `start`.
+    EndPadded {
+        start : usize,
+        width : usize,
+        or_break : bool,
+        info : RenderInfo,
+    },
+}
+
+// The callbacks given to `render_annotated`.
+struct AnnotationHooks<'h, A> {
+    push : &'h mut dyn FnMut(&A) -> String,
+    pop : &'h mut dyn FnMut(&A) -> String,
+    // Whether to also keep a `Region::Annotation` for every annotated
+    // part, for `render_with_spans`.
+    record_spans : bool,
+}
+
+// The kinds of region `render_impl` keeps track of.
+enum Region {
+    Placeholder(PlaceholderId),
+    KeepTogether,
+    // See `render_with_spans`.
+    Annotation,
+    // See `render_detailed`; whether the fold was collapsed.
+    Fold(FoldId, bool),
+}
+
+// The text `render_impl` has produced so far, and where on the current
+// line it's at. Indentation is written lazily: after a newline we only
+// remember how much is owed, and it's paid the first time something is
+// actually written on the new line. That way a line that ends up empty
+// (the newline is followed straight away by another one, or by the end
+// of the document) never gets trailing whitespace. The owed indentation
+// still counts toward `column`, so layout decisions don't change.
+//
+// The text goes to `acc`, which is a `String` unless `render_to` is
+// streaming it somewhere else. Nothing ever reads it back, so the first
+// error the sink reports is kept in `error` and rendering stops there.
+struct Output<W = String> {
+    acc : W,
+    // Bytes written so far, for the extents in `regions`.
+    written : usize,
+    error : Option<core::fmt::Error>,
+    // Display columns written on the current line so far.
+    col : usize,
+    pending_indent : usize,
+    // What to write for `pending_indent` if it isn't plain spaces, i.e.
+    // what `RenderOptions::indent_fn` came up with for this line.
+    pending_indent_text : Option<String>,
+    indent_fn : Option<IndentFn>,
+    // Set for `Indent::Tabs`: indentation is then written as tabs of this
+    // many columns, plus spaces for whatever's left over.
+    indent_tab_width : Option<usize>,
+    line_ending : &'static str,
+    trailing_newline : TrailingNewline,
+    // Line breaks that haven't been written yet, in case they turn out to
+    // be the end of the output; see `RenderOptions::trailing_newline`.
+    held_line_endings : usize,
+    line_no : usize,
+    // The byte extent of each tracked region entered so far, in entry
+    // order.
+    regions : Vec<(Region, core::ops::Range<usize>)>,
+    // The regions from `start_text_region` that nothing has been written
+    // into yet, so they don't have a start of their own.
+    unstarted_regions : Vec<usize>,
+    // The columns recorded by `SetTab` so far.
+    tabs : Map<&'static str, usize>,
+    // `LineSuffix` text waiting for the end of the current line.
+    line_suffix : String,
+    // `RightSuffix` text waiting for the end of the current line, and the
+    // line width to align it to.
+    right_suffix : Option<(String, usize)>,
+    drop_overflowing_right_suffix : bool,
+    // Every group decision made so far, if `render_trace` asked for them.
+    trace : Option<Vec<GroupDecision>>,
+    // The width `render_checked` is checking lines against, and the lines
+    // found to be wider so far.
+    overflows : Option<(usize, Vec<Overflow>)>,
+    // The widest line finished so far.
+    widest : usize,
+    // The byte extent of each line's indentation, for the lines that got
+    // any, if the ANSI renderers asked for them.
+    indents : Option<Vec<core::ops::Range<usize>>>,
+    // `RenderOptions::max_consecutive_blank_lines`, and how many blank
+    // lines have been finished since the last one with something on it.
+    max_blank_lines : Option<usize>,
+    blank_run : usize,
+    // Annotation markup that arrived while indentation was still owed. It
+    // goes out right after the indentation, with the text it's for, instead
+    // of in front of it.
+    pending_markup : String,
+}
+
+impl Output {
+    fn new(options : &RenderOptions) -> Self {
+        Output::with_sink(options, String::new())
+    }
+}
+
+impl<W : core::fmt::Write> Output<W> {
+    fn with_sink(options : &RenderOptions, acc : W) -> Self {
+        let mut out = Output {
+            acc,
+            written : 0,
+            error : None,
+            col : 0,
+            pending_indent : 0,
+            pending_indent_text : None,
+            indent_fn : options.indent_fn.clone(),
+            indent_tab_width : match options.indent {
+                Indent::Tabs if options.tab_width > 0 => Some(options.tab_width),
+                _                                     => None,
+            },
+            line_ending : options.line_ending.as_str(),
+            trailing_newline : options.trailing_newline,
+            held_line_endings : 0,
+            line_no : 0,
+            regions : Vec::new(),
+            unstarted_regions : Vec::new(),
+            tabs : Map::new(),
+            line_suffix : String::new(),
+            right_suffix : None,
+            drop_overflowing_right_suffix : options.drop_overflowing_right_suffix,
+            trace : None,
+            overflows : None,
+            widest : 0,
+            indents : None,
+            max_blank_lines : options.max_consecutive_blank_lines,
+            blank_run : 0,
+            pending_markup : String::new(),
+        };
+        out.start_line(0);
+        out
+    }
+
+    fn start_line(&mut self, nest : usize) {
+        match &self.indent_fn {
+            Some(f) => {
+                let text = (f.borrow_mut())(nest, self.line_no);
+                self.pending_indent = display_width(&text);
+                self.pending_indent_text = Some(text);
+            },
+            None => self.pending_indent = nest,
+        }
+    }
+
+    fn column(&self) -> usize {
+        self.col + self.pending_indent
+    }
+
+    // Whether nothing at all has been put on the current line yet.
+    fn line_is_blank(&self) -> bool {
+        self.col == 0 && self.line_suffix.is_empty() && self.right_suffix.is_none()
+    }
+
+    // A line break the layout produced, which is left out if it would
+    // make the current run of blank lines longer than
+    // `max_consecutive_blank_lines` allows.
+    fn break_line(&mut self, nest : usize) {
+        match self.max_blank_lines {
+            Some(max) if self.blank_run >= max && self.line_is_blank() => self.start_line(nest),
+            _ => self.newline(nest),
+        }
+    }
+
+    fn newline(&mut self, nest : usize) {
+        self.blank_run = if self.line_is_blank() { self.blank_run + 1 } else { 0 };
+        self.flush_suffixes();
+        self.flush_markup();
+        self.end_line();
+        match self.trailing_newline {
+            TrailingNewline::Verbatim => self.write(self.line_ending),
+            _                         => self.held_line_endings += 1,
+        }
+        self.col = 0;
+        self.line_no += 1;
+        self.start_line(nest);
+    }
+
+    // Take note of how wide the line that's just ending came out, and
+    // whether it's gone past `render_checked`'s width.
+    fn end_line(&mut self) {
+        self.widest = self.widest.max(self.col);
+        if let Some((width, overflows)) = self.overflows.as_mut() {
+            if self.col > *width {
+                overflows.push(Overflow { line : self.line_no, width : self.col });
+            }
+        }
+    }
+
+    fn flush_indent(&mut self) {
+        let start = self.written;
+        match self.pending_indent_text.take() {
+            Some(text) => self.write(&text),
+            None => {
+                let mut owed = self.pending_indent;
+                if let Some(tab_width) = self.indent_tab_width {
+                    for _ in 0..owed / tab_width {
+                        self.write("\t");
+                    }
+                    owed %= tab_width;
+                }
+                while owed > 0 {
+                    let n = owed.min(SPACES.len());
+                    self.write(&SPACES[..n]);
+                    owed -= n;
+                }
+            },
+        }
+        self.col += self.pending_indent;
+        self.pending_indent = 0;
+        if let Some(indents) = self.indents.as_mut() {
+            if self.written > start {
+                indents.push(start..self.written);
+            }
+        }
+    }
+
+    // Write out everything that was held back for the end of the line:
+    // line suffixes first, then the right-aligned one.
+    fn flush_suffixes(&mut self) {
+        if !self.line_suffix.is_empty() {
+            let text = core::mem::take(&mut self.line_suffix);
+            self.push_str(&text);
+        }
+        self.flush_right_suffix();
+    }
+
+    fn flush_right_suffix(&mut self) {
+        let Some((text, line_width)) = self.right_suffix.take() else {
+            return
+        };
+        let col = self.column();
+        let width = display_width(&text);
+        // With no line width to speak of there's no right margin to align
+        // to, so the suffix just follows the line's content.
+        if line_width == usize::MAX {
+            self.push_str(" ");
+        } else if col.saturating_add(width) <= line_width {
+            let mut pad = line_width - col - width;
+            while pad > 0 {
+                let n = pad.min(SPACES.len());
+                self.push_str(&SPACES[..n]);
+                pad -= n;
+            }
+        } else if self.drop_overflowing_right_suffix {
+            return
+        } else {
+            self.push_str(" ");
+        }
+        self.push_str(&text);
+    }
+
+    // Enter a region that starts where the next text is written, after
+    // any indentation that's owed for it.
+    fn start_text_region(&mut self, region : Region) -> usize {
+        let idx = self.regions.len();
+        self.regions.push((region, self.written..self.written));
+        self.unstarted_regions.push(idx);
+        idx
+    }
+
+    fn end_region(&mut self, idx : usize) {
+        if let Some(pos) = self.unstarted_regions.iter().position(|i| *i == idx) {
+            self.unstarted_regions.remove(pos);
+            self.regions[idx].1.start = self.written;
+        }
+        self.regions[idx].1.end = self.written;
+    }
+
+    fn push_str(&mut self, s : &str) {
+        if s.is_empty() {
+            return
+        }
+        self.release_line_endings();
+        self.flush_indent();
+        self.flush_markup();
+        for idx in self.unstarted_regions.drain(..) {
+            self.regions[idx].1.start = self.written;
+        }
+        self.write(s);
+        self.col += display_width(s);
+    }
+
+    // Write text that takes up no room on the line, such as the markup
+    // `render_annotated`'s hooks produce.
+    fn markup(&mut self, s : &str) {
+        if self.pending_indent > 0 || !self.pending_markup.is_empty() {
+            self.pending_markup.push_str(s);
+        } else {
+            self.write(s);
+        }
+    }
+
+    fn flush_markup(&mut self) {
+        if !self.pending_markup.is_empty() {
+            let markup = core::mem::take(&mut self.pending_markup);
+            self.write(&markup);
+        }
+    }
+
+    // Write out the line breaks that were held back, now that something
+    // is coming after them.
+    fn release_line_endings(&mut self) {
+        while self.held_line_endings > 0 {
+            self.held_line_endings -= 1;
+            self.write(self.line_ending);
+        }
+    }
+
+    // Write whatever `trailing_newline` says the output ends in, once
+    // everything else is out.
+    fn finish_line_endings(&mut self) {
+        if self.trailing_newline == TrailingNewline::Single && self.written > 0 {
+            self.held_line_endings = 0;
+            self.write(self.line_ending);
+        }
+        self.held_line_endings = 0;
+    }
+
+    fn write(&mut self, s : &str) {
+        if s.is_empty() {
+            return
+        }
+        self.release_line_endings();
+        if self.error.is_none() {
+            if let Err(e) = self.acc.write_str(s) {
+                self.error = Some(e);
+            }
+        }
+        self.written += s.len();
+    }
+
+    // In `render_impl`'s soft-only mode, decide what a held break turns
+    // into now that `upcoming` more columns are about to be written before
+    // the next break: a space (or nothing) if they still fit, a newline
+    // otherwise.
+    fn resolve_soft_break(&mut self, pending_break : &mut Option<(bool, usize)>, upcoming : usize, info : RenderInfo) {
+        if let Some((spaced, nest)) = pending_break.take() {
+            let sep_len = if spaced { 1 } else { 0 };
+            if self.column().saturating_add(sep_len).saturating_add(upcoming) <= info.line_width {
+                if spaced {
+                    self.push_str(" ");
+                }
+            } else {
+                if info.continued {
+                    self.push_str(CONTINUATION);
+                }
+                self.break_line(nest);
+            }
+        }
+    }
+}
+
+// Split a line from `split_inclusive('\n')` into its content and its line
+// ending ("\n", "\r\n", or "" for a last line without one).
+fn split_line_ending(line : &str) -> (&str, &str) {
+    if let Some(content) = line.strip_suffix("\r\n") {
+        (content, "\r\n")
+    } else if let Some(content) = line.strip_suffix('\n') {
+        (content, "\n")
+    } else {
+        (line, "")
+    }
+}
+
+// The smallest multiple of `width` that's at least `col`.
+fn next_tab_stop(col : usize, width : usize) -> usize {
+    if width == 0 {
+        col
+    } else {
+        col.div_ceil(width) * width
+    }
+}
+
+// How many columns `s` takes up on a terminal, which is what all of the
+// layout is measured in: most characters count as one, wide ones (CJK,
+// most emoji) as two, and combining marks as none.
+pub fn display_width(s : &str) -> usize {
+    UnicodeWidthStr::width(s)
+}
+
+// `s` with each of its words (the runs of it between spaces) that's
+// wider than `max` columns cut short by `truncate_token`, for
+// `RenderOptions::max_token_width`.
+fn truncate_tokens(s : &str, max : usize) -> String {
+    let mut acc = String::with_capacity(s.len());
+    for (idx, word) in s.split(' ').enumerate() {
+        if idx > 0 {
+            acc.push(' ');
+        }
+        if display_width(word) > max {
+            acc.push_str(&truncate_token(word, max));
+        } else {
+            acc.push_str(word);
+        }
+    }
+    acc
+}
+
+// As much of `s` as fits in `max` columns with a "…" after it.
+fn truncate_token(s : &str, max : usize) -> String {
+    let Some(room) = max.checked_sub(1) else {
+        return String::new()
+    };
+    let mut acc = String::new();
+    let mut used = 0;
+    for c in s.chars() {
+        used += c.width().unwrap_or(0);
+        if used > room {
+            break
+        }
+        acc.push(c);
+    }
+    acc.push('…');
+    acc
+}
+
+// The line width `Doc::display` renders at if the format string doesn't
+// give one.
+pub const DEFAULT_WIDTH : usize = 80;
+
+// For `render_impl` calls whose options are fresh from `RenderOptions::new`,
+// which doesn't set a node budget.
+const NO_LIMIT : &str = "no node budget was set";
+
+// Indentation is written in pieces of at most this much.
+const SPACES : &str = "                                                                ";
+
+// What `Doc::continued` puts in front of each break.
+const CONTINUATION : &str = " \\";
+
+// `concat` for `optimize`, which joins the pieces of a chain from the
+// left: a `Text` that comes right after another one, at the end of `lhs`,
+// is merged into it instead. The text is appended to in place when
+// nothing else holds it, as is the case from the second merge on, so a
+// long run of them is merged in linear time.
+fn concat_merging_texts<A : 'static>(mut lhs : AnnotatedDoc<A>, rhs : AnnotatedDoc<A>) -> AnnotatedDoc<A> {
+    let Text { s : r, len : r_len } = rhs.as_ref() else {
+        return AnnotatedDoc::concat_owned(lhs, rhs)
+    };
+    fn append(s : &mut Cow<'static, str>, len : &mut usize, r : &str, r_len : usize) {
+        s.to_mut().push_str(r);
+        *len = len.saturating_add(r_len);
+    }
+    match Arc::get_mut(&mut lhs.0) {
+        Some(Text { s, len }) => {
+            append(s, len, r, *r_len);
+            return lhs
+        },
+        Some(Concat { lhs : ll, rhs : lr, dist_newline, flat_len, .. }) => {
+            if let Some(Text { s, len }) = Arc::get_mut(&mut lr.0) {
+                append(s, len, r, *r_len);
+                *flat_len = flat_len.saturating_add(*r_len);
+                if !ll.get_has_newline() {
+                    *dist_newline = dist_newline.saturating_add(*r_len);
+                }
+                return lhs
+            }
+        },
+        _ => (),
+    }
+    let merged = |l : &str, l_len : usize| {
+        let mut s = String::with_capacity(l.len() + r.len());
+        s.push_str(l);
+        s.push_str(r);
+        AnnotatedDoc::from(Text { s : Cow::Owned(s), len : l_len.saturating_add(*r_len) })
+    };
+    let done = match lhs.as_ref() {
+        Text { s : l, len : l_len } => Some(merged(l, *l_len)),
+        Concat { lhs : ll, rhs : lr, .. } => match lr.as_ref() {
+            Text { s : l, len : l_len } => Some(AnnotatedDoc::concat_owned(ll.clone(), merged(l, *l_len))),
+            _ => None,
+        },
+        _ => None,
+    };
+    done.unwrap_or_else(|| AnnotatedDoc::concat_owned(lhs, rhs))
+}
+
+// The identity transformation for `render_impl`'s text hook.
+fn unmapped(s : &str) -> Cow<'_, str> {
+    Cow::Borrowed(s)
+}
+
+// Take a list of documents and make a tree by concatenating them.
+// IE turn [d1, d2, d3, d4] into :
+//             C
+//           /   \
+//         C     d4
+//       /  \
+//     C    d3
+//   /  \
+//  d1  d2
+pub fn sep<A : 'static>(docs : &[AnnotatedDoc<A>]) -> AnnotatedDoc<A> {
+    docs.iter().cloned().collect()
+}
+
+// Append `sep` to every one of `docs` but the last, e.g. to get the commas
+// of `a, b, c`. The result is meant to be fed to `sep`, `fill` and the
+// like, which decide what goes between the elements.
+pub fn punctuate<A : 'static>(sep : AnnotatedDoc<A>, docs : &[AnnotatedDoc<A>]) -> Vec<AnnotatedDoc<A>> {
+    let last = docs.len().saturating_sub(1);
+    docs.iter().enumerate().map(|(idx, doc)| {
+        if idx < last {
+            doc.concat(sep.clone())
+        } else {
+            doc.clone()
+        }
+    }).collect()
+}
+
+// Collects the pieces of a document one at a time, e.g. in a loop, and
+// concatenates them into a balanced tree of `Concat`s, rather than the
+// chain `acc = acc.concat(next)` (or `sep`) makes, which is as deep as it
+// is long. It works like counting in binary: the pieces pushed so far are
+// held as complete subtrees of 1, 2, 4, ... pieces, at most one of each
+// size, and a piece that arrives is joined with the subtree before it
+// while the two are the same size. Each `Concat`'s cached measurements
+// come straight from its two halves, so this is a single pass, and no
+// subtree is cloned along the way. `finish` joins the few subtrees that
+// are left. The document renders the same as the chain would. See
+// benches/doc_builder.rs.
+#[derive(Debug)]
+pub struct DocBuilder<A : 'static = ()> {
+    // The complete subtrees, oldest first, with how many pieces are in
+    // each; the sizes are decreasing powers of two.
+    subtrees : Vec<(AnnotatedDoc<A>, usize)>,
+    len : usize,
+}
+
+impl<A : 'static> DocBuilder<A> {
+    pub fn new() -> Self {
+        DocBuilder { subtrees : Vec::new(), len : 0 }
+    }
+
+    // `capacity` is how many pieces are coming; the builder only ever
+    // holds about its logarithm's worth of subtrees.
+    pub fn with_capacity(capacity : usize) -> Self {
+        let subtrees = capacity.checked_ilog2().map_or(0, |log| log as usize + 1);
+        DocBuilder { subtrees : Vec::with_capacity(subtrees), len : 0 }
+    }
+
+    pub fn push(&mut self, doc : impl Into<AnnotatedDoc<A>>) {
+        let (mut doc, mut size) = (doc.into(), 1);
+        while let Some((prev, prev_size)) = self.subtrees.pop() {
+            if prev_size != size {
+                self.subtrees.push((prev, prev_size));
+                break
+            }
+            doc = AnnotatedDoc::concat_owned(prev, doc);
+            size *= 2;
+        }
+        self.subtrees.push((doc, size));
+        self.len += 1;
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // Everything pushed so far, concatenated in order; `nil` if nothing
+    // was.
+    pub fn finish(self) -> AnnotatedDoc<A> {
+        self.subtrees.into_iter().rev().fold(AnnotatedDoc::nil(), |acc, (doc, _)| AnnotatedDoc::concat_owned(doc, acc))
+    }
+}
+
+impl<A : 'static> Default for DocBuilder<A> {
+    fn default() -> Self {
+        DocBuilder::new()
+    }
+}
+
+// Hands out one shared copy of each distinct subtree, so a document with
+// the same fragment in many places (a keyword, a run of punctuation) only
+// holds on to it once. The same copy is handed out across calls to
+// `intern`, so documents interned separately share their common parts
+// too, and every one of them stays alive for as long as the interner
+// does. Only with the `std` feature.
+#[cfg(feature = "std")]
+pub struct DocInterner<A : 'static = ()> {
+    nodes : std::collections::HashSet<Shallow<A>>,
+}
+
+#[cfg(feature = "std")]
+impl<A : Clone + Eq + core::hash::Hash + 'static> DocInterner<A> {
+    pub fn new() -> Self {
+        DocInterner { nodes : std::collections::HashSet::new() }
+    }
+
+    // How many distinct nodes have been seen so far.
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    // A document equal to `doc` (by `==`) in which every subtree is the
+    // interner's copy of it. Subtrees are dealt with children first, so by
+    // the time a node is looked up its children are already the shared
+    // copies, and it only has to be compared one level deep.
+    pub fn intern(&mut self, doc : &AnnotatedDoc<A>) -> AnnotatedDoc<A> {
+        // What's been made of each node of `doc` so far, by address. `doc`
+        // is borrowed throughout, so none of them can go away and have
+        // their address reused.
+        let mut done = std::collections::HashMap::<*const InnerDoc<A>, AnnotatedDoc<A>>::new();
+        let mut todo = vec![(doc, false)];
+        while let Some((node, children_done)) = todo.pop() {
+            if done.contains_key(&Arc::as_ptr(&node.0)) {
+                continue
+            }
+            if !children_done {
+                todo.push((node, true));
+                todo.extend(node.as_ref().children().into_iter().map(|child| (child, false)));
+                continue
+            }
+            let children = node.as_ref().children();
+            let unchanged = children.iter().all(|child| Arc::ptr_eq(&child.0, &done[&Arc::as_ptr(&child.0)].0));
+            let rebuilt = if unchanged {
+                node.clone()
+            } else {
+                let mut inner = node.as_ref().clone();
+                for slot in inner.children_mut() {
+                    *slot = done[&Arc::as_ptr(&slot.0)].clone();
+                }
+                AnnotatedDoc::from(inner)
+            };
+            let canonical = match self.nodes.get(&Shallow(rebuilt.clone())) {
+                Some(Shallow(shared)) => shared.clone(),
+                None => {
+                    self.nodes.insert(Shallow(rebuilt.clone()));
+                    rebuilt
+                },
+            };
+            done.insert(Arc::as_ptr(&node.0), canonical);
+        }
+        done[&Arc::as_ptr(&doc.0)].clone()
+    }
+}
+
+#[cfg(feature = "std")]
+impl<A : Clone + Eq + core::hash::Hash + 'static> Default for DocInterner<A> {
+    fn default() -> Self {
+        DocInterner::new()
+    }
+}
+
+// A node whose children are all an interner's copies, compared and hashed
+// one level deep: its own contents, plus which copies its children are.
+#[cfg(feature = "std")]
+struct Shallow<A : 'static>(AnnotatedDoc<A>);
+
+#[cfg(feature = "std")]
+impl<A : PartialEq + 'static> PartialEq for Shallow<A> {
+    fn eq(&self, other : &Self) -> bool {
+        let (lhs, rhs) = (self.0.as_ref().children(), other.0.as_ref().children());
+        // With the children the same nodes, `structurally_eq` only has the
+        // top level left to look at.
+        lhs.len() == rhs.len()
+        && lhs.iter().zip(rhs.iter()).all(|(l, r)| Arc::ptr_eq(&l.0, &r.0))
+        && self.0.structurally_eq(&other.0)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<A : Eq + 'static> Eq for Shallow<A> {}
+
+#[cfg(feature = "std")]
+impl<A : core::hash::Hash + 'static> core::hash::Hash for Shallow<A> {
+    fn hash<H : core::hash::Hasher>(&self, state : &mut H) {
+        let mut children = Vec::new();
+        self.0.as_ref().hash_own(state, &mut children);
+        for child in children {
+            (child as *const InnerDoc<A>).hash(state);
+        }
     }
- 
+}
 
-    pub fn as_parenable_max(self) -> Parenable {
-        Parenable::new_max(self)
+// Concatenate `items` with `separator` in between each pair of them:
+// `a sep b sep c`, and `nil` for no items.
+pub fn join<A : 'static>(items : &[AnnotatedDoc<A>], separator : &AnnotatedDoc<A>) -> AnnotatedDoc<A> {
+    let mut as_iter = items.iter().cloned();
+    match as_iter.next() {
+        None => AnnotatedDoc::nil(),
+        Some(fst) => as_iter.fold(fst, |acc, next| acc.concat(separator.clone()).concat(next))
     }
+}
 
-    pub fn as_parenable(self, priority : usize) -> Parenable {
-        Parenable::new(self, priority)
-    }
+// Where `enclose_sep_with` puts the separators when the list is broken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SepPlacement {
+    // At the start of each line but the first, lined up under `open`:
+    //
+    //   [a
+    //   , b
+    //   , c
+    //   ]
+    Leading,
+    // At the end of each line but the last, the items lined up just past
+    // `open`:
+    //
+    //   [a,
+    //    b,
+    //    c
+    //   ]
+    Trailing,
+}
 
+// A bracketed list that's all on one line if it fits, and has an item per
+// line otherwise, with `close` on a line of its own. The separators lead;
+// see `enclose_sep_with`.
+pub fn enclose_sep<A : 'static>(open : AnnotatedDoc<A>,
+                      close : AnnotatedDoc<A>,
+                      sep : AnnotatedDoc<A>,
+                      items : &[AnnotatedDoc<A>]) -> AnnotatedDoc<A> {
+    enclose_sep_with(open, close, sep, items, SepPlacement::Leading)
 }
 
-// Take a list of documents and make a tree by concatenating them.
-// IE turn [d1, d2, d3, d4] into :
-//             C
-//           /   \
-//         C     d4
-//       /  \
-//     C    d3
-//   /  \
-//  d1  d2
-pub fn sep(docs : &[Doc]) -> Doc {
-    let mut as_iter = docs.into_iter().cloned();
-    match as_iter.next() {
-        None => Doc::nil(),
-        Some(fst) => as_iter.fold(fst, |acc, next| acc.concat(next))
-    }
+// `enclose_sep`, with the separators placed as `placement` says. `sep` is
+// written exactly as given between two items, with nothing added on the
+// one-line layout for `Leading` (so use `", "` to get `[a, b, c]`), and a
+// space (or the break) after it for `Trailing` (so use `","`). With no
+// items at all it's just `open` and `close`.
+pub fn enclose_sep_with<A : 'static>(open : AnnotatedDoc<A>,
+                           close : AnnotatedDoc<A>,
+                           sep : AnnotatedDoc<A>,
+                           items : &[AnnotatedDoc<A>],
+                           placement : SepPlacement) -> AnnotatedDoc<A> {
+    let mut as_iter = items.iter().cloned();
+    let Some(fst) = as_iter.next() else {
+        return open.concat(close)
+    };
+    let body = match placement {
+        SepPlacement::Leading => {
+            let items = as_iter.fold(fst, |acc, item| {
+                acc.concat(AnnotatedDoc::softbreak()).concat(sep.clone()).concat(item)
+            });
+            open.concat(items)
+        },
+        SepPlacement::Trailing => {
+            let items = as_iter.fold(fst, |acc, item| {
+                acc.concat(sep.clone()).concat(AnnotatedDoc::softline()).concat(item)
+            });
+            open.concat(items.align())
+        },
+    };
+    body.concat(AnnotatedDoc::softbreak()).concat(close).align().group()
+}
+
+// Lay `docs` out like the words of a paragraph: as many as fit on each
+// line, with a space between them, breaking only in front of one that
+// wouldn't fit anymore. Unlike `word_wrap_val`, the decision is made one
+// element at a time, from the current column. An element that's wider
+// than the whole line gets a line of its own (and overflows it). If
+// everything fits on the current line, it all goes flat. That's the
+// `LineBreak::Greedy` way; see `RenderOptions::line_break` for the other.
+pub fn fill<A : 'static>(docs : &[AnnotatedDoc<A>]) -> AnnotatedDoc<A> {
+    fill_joined(docs, None)
+}
+
+// Like `fill`, with `sep` after every element but the last, e.g. `,` for a
+// list. Each `sep` stays on the line with the element before it.
+pub fn fill_sep<A : 'static>(docs : &[AnnotatedDoc<A>], sep : AnnotatedDoc<A>) -> AnnotatedDoc<A> {
+    fill_joined(docs, Some(sep))
 }
 
+fn fill_joined<A : 'static>(docs : &[AnnotatedDoc<A>], sep : Option<AnnotatedDoc<A>>) -> AnnotatedDoc<A> {
+    let mut as_iter = docs.iter().cloned();
+    let joined = match as_iter.next() {
+        None => return AnnotatedDoc::nil(),
+        Some(fst) => as_iter.fold(fst, |acc, next| {
+            let acc = match &sep {
+                Some(sep) => acc.concat(sep.clone()),
+                None => acc,
+            };
+            acc.concat(AnnotatedDoc::newline()).concat(next)
+        }),
+    };
+    AnnotatedDoc::from(Fill {
+        has_newline : joined.get_has_newline(),
+        dist_newline : joined.get_dist_newline(),
+        flat_len : joined.get_flat_len(),
+        doc : joined,
+    })
+}
+
+// For `LineBreak::Optimal`: given the widths of a fill's elements, where
+// the first one starts, the indentation of the lines after a break, the
+// line width and how much has to fit after the last element, whether to
+// break in each of the gaps between them. The cost of a line but the last
+// is the square of the room left at its end; an element too wide for any
+// line goes on one of its own at no cost, as it would greedily.
+fn optimal_breaks(widths : &[usize], start : usize, indent : usize, line_width : usize, trailing : usize) -> Vec<bool> {
+    let n = widths.len();
+    // `best[i]`: the cheapest way to lay out elements `i..` from the start
+    // of a line, and where that line ends.
+    let mut best = vec![(0usize, n); n + 1];
+    for i in (0..n).rev() {
+        let col = if i == 0 { start } else { indent };
+        best[i] = (usize::MAX, i + 1);
+        let mut end = col;
+        for j in (i + 1)..=n {
+            end = end.saturating_add(widths[j - 1]);
+            if j > i + 1 {
+                end = end.saturating_add(1);
+            }
+            let used = if j == n { end.saturating_add(trailing) } else { end };
+            if used > line_width && j > i + 1 {
+                break
+            }
+            let slack = line_width.saturating_sub(used);
+            let cost = if j == n { 0 } else { slack.saturating_mul(slack) };
+            let total = cost.saturating_add(best[j].0);
+            if total < best[i].0 {
+                best[i] = (total, j);
+            }
+        }
+    }
+    let mut breaks = vec![false; n.saturating_sub(1)];
+    let mut i = 0;
+    while best[i].1 < n {
+        i = best[i].1;
+        breaks[i - 1] = true;
+    }
+    breaks
+}
 
 /*
  turn an iterator [d1, d2, d3, d4] into
@@ -321,40 +4256,1131 @@ pub fn sep(docs : &[Doc]) -> Doc {
                      |
                     C (\n, d2)
 */
-pub fn word_wrap_val<I>(mut s : I) -> Doc 
-where I : Iterator<Item = Doc> + Clone {
+pub fn word_wrap_val<A : 'static, I>(mut s : I) -> AnnotatedDoc<A> 
+where I : Iterator<Item = AnnotatedDoc<A>> + Clone {
     if let Some(hd) = s.next() {
-        s.fold(hd, |acc, elem| acc.concat(Doc::line().concat(elem).group()))
+        s.fold(hd, |acc, elem| acc.concat(AnnotatedDoc::line().concat(elem).group()))
     } else {
-        Doc::nil()
+        AnnotatedDoc::nil()
+    }
+}
+
+// `word_wrap_val` of the words of `s`, a paragraph of plain text: any
+// run of ASCII whitespace (newlines included) is one place it can wrap,
+// and whitespace at either end is dropped. `nil` if there are no words.
+// For annotated words, build them and use `word_wrap_val`.
+pub fn word_wrap_str(s : &str) -> Doc {
+    word_wrap_val(s.split_ascii_whitespace().map(|word| Doc::text(word.to_string())))
+}
+
+// `word_wrap_str` for text made up of paragraphs, separated by blank
+// lines (ones that are empty or only whitespace). Each paragraph is
+// wrapped on its own, and they're laid out with one blank line between
+// them however many there were in `s`. A word too long for the line is
+// left sticking out past the end of it.
+pub fn word_wrap_paragraphs(s : &str) -> Doc {
+    let mut paragraphs = Vec::new();
+    let mut current = String::new();
+    for line in s.lines() {
+        if line.trim().is_empty() {
+            if !current.is_empty() {
+                paragraphs.push(word_wrap_str(&current));
+                current.clear();
+            }
+        } else {
+            current.push_str(line);
+            current.push('\n');
+        }
+    }
+    if !current.is_empty() {
+        paragraphs.push(word_wrap_str(&current));
+    }
+    join(&paragraphs, &Doc::hardline().concat(Doc::hardline()))
+}
+
+
+// Stack numbers in a column, one per line, lined up on their decimal
+// points. Each number is taken in its flat form; the part before the
+// '.' (sign included) is right-aligned, and the '.' and everything after
+// it are padded on the right to the widest fractional part, so every row
+// comes out the same width. Integers (no '.') line up as if the point
+// were just past their last digit. The rows are separated by hard line
+// breaks, so the column stays a column inside a group that fits. Panics
+// if a number has a hard line break of its own.
+//
+//   ["3.14", "12.5", "100"]  =>   3.14
+//                                12.5
+//                               100
+pub fn align_decimal<A : 'static>(numbers : &[AnnotatedDoc<A>]) -> AnnotatedDoc<A> {
+    let texts = numbers.iter().map(|n| n.render_flat()).collect::<Vec<String>>();
+    assert!(texts.iter().all(|t| !t.contains('\n')), "align_decimal given a number with a hard line break");
+    fn split(t : &str) -> (&str, &str) {
+        t.split_at(t.find('.').unwrap_or(t.len()))
+    }
+    let int_width = texts.iter().map(|t| display_width(split(t).0)).max().unwrap_or(0);
+    let frac_width = texts.iter().map(|t| display_width(split(t).1)).max().unwrap_or(0);
+
+    let rows = texts.iter().map(|t| {
+        let (int, frac) = split(t);
+        let row = format!("{}{}{}", " ".repeat(int_width - display_width(int)), t, " ".repeat(frac_width - display_width(frac)));
+        AnnotatedDoc::text(row)
+    }).collect::<Vec<AnnotatedDoc<A>>>();
+
+    let mut rows = rows.into_iter();
+    match rows.next() {
+        None => AnnotatedDoc::nil(),
+        Some(fst) => rows.fold(fst, |acc, row| acc.concat(AnnotatedDoc::hardline()).concat(row)),
     }
 }
 
+// `{ key: value, ... }` on one line if it fits, and otherwise a pair per
+// line, nested by one unit of `RenderOptions::indent`, with each key
+// padded out (see `Doc::fill`) to the widest key, as measured flat, so
+// that the colons line up. With `Indent::Spaces(2)`:
+//
+//   {
+//     name: "Ferris",
+//     age : 7
+//   }
+//
+// The keys are padded in the one-line form too. The block is laid out
+// against a line `width` wide (see `Doc::with_width`), whatever the rest
+// of the document is rendered at. With no pairs it's just `{}`.
+pub fn key_value_block<A : 'static>(pairs : &[(AnnotatedDoc<A>, AnnotatedDoc<A>)], width : usize) -> AnnotatedDoc<A> {
+    if pairs.is_empty() {
+        return AnnotatedDoc::static_text("{}")
+    }
+    let key_width = pairs.iter().map(|(key, _)| key.get_flat_len()).max().unwrap_or(0);
+    let rows = pairs.iter().map(|(key, value)| {
+        key.clone().fill(key_width).concat(": ").concat(value.clone())
+    }).collect::<Vec<_>>();
+    let body = AnnotatedDoc::line()
+               .concat(join(&rows, &AnnotatedDoc::static_text(",").concat(AnnotatedDoc::line())))
+               .nest(1);
+    let block = AnnotatedDoc::static_text("{").concat(body).concat(AnnotatedDoc::line()).concat("}").group();
+    AnnotatedDoc::with_width(width, block)
+}
+
+// Lets anything that converts into a `Doc` (including everything that's
+// `Display`, through the impl below) be rendered directly, as in
+// `my_ast.pretty(80)`, without spelling out `.into()` first.
+pub trait Pretty {
+    fn pretty(&self, width : usize) -> String;
+}
+
+impl<T> Pretty for T
+where T : Clone + Into<Doc> {
+    fn pretty(&self, width : usize) -> String {
+        let doc : Doc = self.clone().into();
+        doc.render(width)
+    }
+}
 
-impl<T> From<T> for Doc 
-where T : std::fmt::Display {
-    fn from(t : T) -> Doc {
-        Doc::text(format!("{}", t))
+// A value's `Display` output can run over several lines, so this goes
+// through `text_multiline` when it does.
+impl<A : 'static, T> From<T> for AnnotatedDoc<A> 
+where T : core::fmt::Display {
+    fn from(t : T) -> Self {
+        let s = format!("{}", t);
+        if s.contains('\n') {
+            AnnotatedDoc::text_multiline(&s)
+        } else {
+            AnnotatedDoc::text(s)
+        }
     }
 }
 
-impl std::convert::AsRef<InnerDoc> for Doc {
-    fn as_ref(&self) -> &InnerDoc {
+impl<A> core::convert::AsRef<InnerDoc<A>> for AnnotatedDoc<A> {
+    fn as_ref(&self) -> &InnerDoc<A> {
         match self {
-            Doc(x) => x.as_ref()
+            AnnotatedDoc(x) => x.as_ref()
         }
     }
 }
 
-impl From<InnerDoc> for Doc {
-    fn from(t : InnerDoc) -> Doc {
-        Doc(Arc::new(t))
+impl<A> From<InnerDoc<A>> for AnnotatedDoc<A> {
+    fn from(t : InnerDoc<A>) -> Self {
+        AnnotatedDoc(Arc::new(t))
+    }
+}
+
+impl<A : Clone> From<&InnerDoc<A>> for AnnotatedDoc<A> {
+    fn from(t : &InnerDoc<A>) -> Self {
+        AnnotatedDoc(Arc::new(t.clone()))
+    }
+}
+
+
+// `a + b` is `a.concat(b)`, so a chain of `+` builds the same tree as a
+// chain of `concat` calls.
+impl<A : 'static> core::ops::Add for AnnotatedDoc<A> {
+    type Output = Self;
+    fn add(self, rhs : Self) -> Self {
+        self.concat(rhs)
+    }
+}
+
+impl<A : 'static> core::ops::Add<&AnnotatedDoc<A>> for AnnotatedDoc<A> {
+    type Output = Self;
+    fn add(self, rhs : &Self) -> Self {
+        self.concat(rhs.clone())
+    }
+}
+
+impl<A : 'static> core::ops::AddAssign for AnnotatedDoc<A> {
+    fn add_assign(&mut self, rhs : Self) {
+        *self = self.concat(rhs);
+    }
+}
+
+impl<A : 'static> core::ops::AddAssign<&AnnotatedDoc<A>> for AnnotatedDoc<A> {
+    fn add_assign(&mut self, rhs : &Self) {
+        *self = self.concat(rhs.clone());
     }
 }
 
-impl From<&InnerDoc> for Doc {
-    fn from(t : &InnerDoc) -> Doc {
-        Doc(Arc::new(t.clone()))
+// Concatenate everything the iterator yields, left to right; an empty
+// iterator gives `Doc::nil()`. This is what `sep` does with a slice.
+impl<A : 'static> core::iter::FromIterator<AnnotatedDoc<A>> for AnnotatedDoc<A> {
+    fn from_iter<I : IntoIterator<Item = Self>>(iter : I) -> Self {
+        let mut as_iter = iter.into_iter();
+        match as_iter.next() {
+            None => AnnotatedDoc::nil(),
+            Some(fst) => as_iter.fold(fst, AnnotatedDoc::concat_owned)
+        }
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A document nested this deep would overflow the stack on the first
+    // recursive walk over it.
+    const DEEP : usize = 500_000;
+
+    #[test]
+    fn deeply_nested_groups_render() {
+        let mut doc = Doc::from("x");
+        for _ in 0..DEEP {
+            doc = doc.group();
+        }
+        assert_eq!(doc.render(80), "x");
+        assert_eq!(doc.flatten().render(80), "x");
+        assert_eq!(doc.count_lines(80), 1);
+    }
+
+    // Feed `doc` through `render_prefix` one cell at a time, the way it's
+    // meant to be used, and collect the cells.
+    fn prefix_cells(doc : &Doc, width : usize) -> Vec<String> {
+        let mut cells = Vec::new();
+        let mut rest = Some(doc.clone());
+        while let Some(doc) = rest {
+            let (cell, tail) = doc.render_prefix(width);
+            cells.push(cell);
+            rest = tail;
+            assert!(cells.len() < 100, "render_prefix isn't making progress");
+        }
+        cells
+    }
+
+    #[test]
+    fn render_prefix_splits_at_width() {
+        let doc = Doc::from("hello").concat_newline("world");
+        let (head, tail) = doc.render_prefix(5);
+        assert_eq!(head, "hello");
+        assert_eq!(tail.map(|tail| tail.render(80)), Some(String::from("world")));
+        assert_eq!(prefix_cells(&Doc::from("abcdefg"), 3), ["abc", "def", "g"]);
+    }
+
+    #[test]
+    fn render_prefix_loop_ends() {
+        let doc = Doc::from("a").concat(Doc::hardline()).concat("b");
+        assert_eq!(prefix_cells(&doc, 80), ["a", "b"]);
+        let blank = Doc::from("a").concat(Doc::user_blank_line()).concat(Doc::hardline());
+        assert_eq!(prefix_cells(&blank, 80), ["a", ""]);
+        assert_eq!(prefix_cells(&Doc::from("ab"), 0), ["a", "b"]);
+    }
+
+    #[test]
+    fn right_align_suffix_pads_to_the_margin() {
+        let line = |s : &str| Doc::from(s).concat(Doc::right_align_suffix(Doc::from("OK")));
+        let doc = line("short").concat(Doc::hardline()).concat(line("a longer line"));
+        let out = doc.render(40);
+        assert!(out.lines().all(|l| l.len() == 40 && l.ends_with("OK")), "{:?}", out);
+        assert_eq!(line("a").render(usize::MAX), "a OK");
+        let two_lines = line("a").concat(Doc::hardline()).concat(line("b"));
+        assert_eq!(two_lines.min_width_for_lines(2), Some(0));
+        assert_eq!(two_lines.min_width_for_lines(1), None);
+    }
+
+    fn node_count(doc : &Doc) -> usize {
+        doc.debug_tree().lines().count()
+    }
+
+    #[test]
+    fn optimize_merges_adjacent_texts() {
+        let letters = (0..100).map(|i| Doc::text(((b'a' + i % 26) as char).to_string()));
+        let left = letters.clone().fold(Doc::nil(), |acc, c| acc.concat(c));
+        let right = letters.rev().fold(Doc::nil(), |acc, c| c.concat(acc));
+        assert_eq!(node_count(&left), 199);
+        for doc in [&left, &right] {
+            let optimized = doc.optimize();
+            assert_eq!(node_count(&optimized), 1);
+            assert_eq!(optimized.render(10), doc.render(10));
+            assert_eq!(optimized.get_flat_len(), 100);
+        }
+
+        let doc = Doc::from("fn").concat(" ").concat("foo")
+                  .concat(Doc::newline()).concat("{").concat("}").nest(2).group();
+        let optimized = doc.optimize();
+        assert_eq!(optimized.text_leaves().collect::<Vec<_>>(), ["fn foo", "{}"]);
+        for width in [1, 6, 80] {
+            assert_eq!(optimized.render(width), doc.render(width));
+        }
+    }
+
+    #[test]
+    fn optimize_is_linear_in_long_runs() {
+        let doc = (0..200_000).fold(Doc::nil(), |acc, _| acc.concat("x"));
+        assert_eq!(doc.optimize().text_leaves().collect::<Vec<_>>(), ["x".repeat(200_000)]);
+    }
+
+    #[test]
+    fn concat_keeps_text_atoms_apart() {
+        let doc = Doc::from("user").concat_space("secret").concat_space("end");
+        assert_eq!(doc.text_leaves().collect::<Vec<_>>(), ["user", " ", "secret", " ", "end"]);
+        fn redact(s : &str) -> Cow<'_, str> {
+            if s == "secret" { Cow::Owned("*".repeat(s.len())) } else { Cow::Borrowed(s) }
+        }
+        assert_eq!(doc.render_map_text(80, redact), "user ****** end");
+    }
+
+    #[test]
+    fn max_token_width_cuts_each_long_token() {
+        let mut options = RenderOptions::new(80);
+        options.max_token_width = Some(6);
+        let atoms = Doc::from("foo").concat_space("verylongidentifier").concat_space("bar");
+        assert_eq!(atoms.render_with(&options), "foo veryl… bar");
+        assert_eq!(atoms.optimize().render_with(&options), "foo veryl… bar");
+        assert_eq!(Doc::from("a sixsix sevenen").render_with(&options), "a sixsix seven…");
+        options.max_token_width = Some(1);
+        assert_eq!(Doc::from("ab c").render_with(&options), "… c");
+        options.max_token_width = Some(0);
+        assert_eq!(Doc::from("ab").render_with(&options), "");
+    }
+
+    #[test]
+    fn internal_indentation_is_in_columns() {
+        let mut options = RenderOptions::new(10);
+        options.indent = Indent::Spaces(4);
+        let body = Doc::from("aaaa").concat(Doc::newline()).concat("bbbb").group();
+        let doc = Doc::from("x").concat(Doc::hardline())
+                  .concat(Doc::with_line_count(|n| Doc::text(format!("{}:", n)), body))
+                  .nest(1);
+        assert_eq!(doc.render_with(&options), "x\n    2:aaaa\n    bbbb");
+
+        let items = [Doc::from("one"), Doc::from("two")];
+        let list = enclose_sep_with(Doc::from("["), Doc::from("]"), Doc::from(","), &items, SepPlacement::Trailing);
+        options.line_width = 6;
+        assert_eq!(list.render_with(&options), "[one,\n two\n]");
+    }
+
+    fn nested_line_counts(depth : usize) -> Doc {
+        let mut doc = Doc::from("a").concat(Doc::hardline()).concat("b");
+        for _ in 0..depth {
+            doc = Doc::with_line_count(|n| Doc::text(format!("{}:", n)).concat(Doc::hardline()), doc);
+        }
+        doc
+    }
+
+    #[test]
+    fn line_count_counts_laid_out_lines() {
+        let body = Doc::from("one").concat(Doc::newline()).concat("two").group();
+        let doc = Doc::with_line_count(|n| Doc::text(format!("{} ", n)), body);
+        assert_eq!(doc.render(80), "1 one two");
+        assert_eq!(doc.render(5), "2 one\ntwo");
+        assert_eq!(nested_line_counts(2).render(80), "3:\n2:\na\nb");
+    }
+
+    #[test]
+    fn line_count_probes_share_the_node_budget() {
+        let doc = nested_line_counts(40);
+        assert_eq!(doc.render(80).lines().count(), 42);
+        let mut options = RenderOptions::new(80);
+        options.max_nodes = Some(1000);
+        assert_eq!(doc.try_render_with(&options), Err(RenderLimit { max_nodes : 1000 }));
+    }
+
+    #[test]
+    fn soft_only_ignores_hard_breaks() {
+        let doc = Doc::from("one two").concat(Doc::hardline()).concat(Doc::hardline())
+                  .concat("three four").concat(Doc::newline()).concat("five");
+        assert_eq!(doc.render_soft_only(80), "one two three four five");
+        assert_eq!(doc.render_soft_only(12), "one two\nthree four\nfive");
+        let blank = Doc::from("a").concat(Doc::user_blank_line()).concat("b");
+        assert_eq!(blank.render_soft_only(80), "a b");
+
+        let paragraph = |words : &str| words.split(' ').map(Doc::from).reduce(Doc::concat_newline).unwrap().group();
+        let doc = paragraph("the first paragraph").concat(Doc::user_blank_line())
+                  .concat(paragraph("a second one")).concat(Doc::hardline()).concat(Doc::hardline())
+                  .concat(paragraph("and a third"));
+        assert_eq!(doc.render_soft_only(24), "the first paragraph a\nsecond one and a third");
+    }
+
+    #[test]
+    fn word_wrap_str_wraps_words() {
+        assert_eq!(word_wrap_str("  the quick\n brown fox ").render(10), "the quick\nbrown fox");
+        assert_eq!(word_wrap_str(" \n ").render(10), "");
+    }
+
+    #[test]
+    fn word_wrap_paragraphs_keeps_one_blank_line() {
+        let text = "one two three\n\n\n  \nfour five\n";
+        assert_eq!(word_wrap_paragraphs(text).render(8), "one two\nthree\n\nfour\nfive");
+        assert_eq!(word_wrap_paragraphs(text).render(80), "one two three\n\nfour five");
+    }
+
+    #[test]
+    fn multi_line_display_values_become_lines() {
+        struct Two;
+        impl core::fmt::Display for Two {
+            fn fmt(&self, f : &mut core::fmt::Formatter) -> core::fmt::Result {
+                write!(f, "first\nsecond")
+            }
+        }
+        let doc = Doc::from("x ").concat(Doc::from(Two)).nest(1);
+        assert_eq!(doc.render(80), "x first\n second");
+        assert_eq!(String::from("a\r\nb").pretty(80), "a\nb");
+    }
+
+    #[test]
+    fn count_lines_matches_render() {
+        let doc = word_wrap_str("a few words to wrap at several widths")
+                  .concat(Doc::hardline()).concat("end");
+        for width in [0, 5, 10, 20, 80] {
+            assert_eq!(doc.count_lines(width), doc.render(width).lines().count());
+        }
+    }
+
+    #[test]
+    fn key_value_block_lines_up_colons() {
+        let pairs = [(Doc::from("name"), Doc::from("\"Ferris\"")), (Doc::from("age"), Doc::from(7))];
+        assert_eq!(key_value_block(&pairs, 80).render(80), "{ name: \"Ferris\", age : 7 }");
+        assert_eq!(key_value_block(&pairs, 20).render(80), "{\n name: \"Ferris\",\n age : 7\n}");
+        let mut options = RenderOptions::new(80);
+        options.indent = Indent::Spaces(4);
+        assert_eq!(key_value_block(&pairs, 20).render_with(&options), "{\n    name: \"Ferris\",\n    age : 7\n}");
+        assert_eq!(key_value_block::<()>(&[], 20).render(80), "{}");
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn common_leaves_are_shared() {
+        assert!(Arc::ptr_eq(&Doc::space().0, &Doc::space().0));
+        assert!(Arc::ptr_eq(&Doc::line().0, &Doc::softline().0));
+        assert!(Arc::ptr_eq(&Doc::nil().0, &Doc::nil().0));
+        fn leftmost(mut doc : Doc) -> Doc {
+            while let Concat { lhs, .. } = doc.as_ref() {
+                doc = lhs.clone();
+            }
+            doc
+        }
+        let open = |inner : &str| leftmost(Doc::from(inner).surround_paren());
+        assert!(Arc::ptr_eq(&open("a").0, &open("b").0));
+        assert!(!Arc::ptr_eq(&AnnotatedDoc::<u8>::space().0, &AnnotatedDoc::<u8>::space().0));
+    }
+
+    #[test]
+    fn collapsed_fold_renders_its_summary() {
+        let body = Doc::from("{").concat(Doc::hardline().concat("a;").concat(Doc::hardline()).concat("b;").nest(2))
+                   .concat(Doc::hardline()).concat("}");
+        let doc = Doc::from("fn f() ").concat(Doc::foldable(FoldId(0), Doc::from("{ ... }"), body))
+                  .concat(Doc::hardline()).concat("end");
+        let mut options = RenderOptions::new(80);
+        let (open, folds) = doc.render_detailed(&options);
+        assert_eq!(open, "fn f() {\n  a;\n  b;\n}\nend");
+        assert_eq!(folds, vec![FoldMarker { id : FoldId(0), range : 7..20, lines : 0..4, collapsed : false }]);
+
+        options.collapsed.insert(FoldId(0));
+        let (closed, folds) = doc.render_detailed(&options);
+        assert_eq!(closed, "fn f() { ... }\nend");
+        assert_eq!(folds, vec![FoldMarker { id : FoldId(0), range : 7..14, lines : 0..1, collapsed : true }]);
+        assert_eq!(doc.render_with(&options), closed);
+    }
+
+    #[test]
+    fn block_comment_stays_whole_in_an_argument_list() {
+        let args = [Doc::from("first"), Doc::block_comment("the  second\nargument"), Doc::from("third")];
+        let call = Doc::from("f").concat(enclose_sep(Doc::from("("), Doc::from(")"), Doc::from(","), &args));
+        for width in [10, 30, 80] {
+            let out = call.render(width);
+            assert!(out.contains("/* the second argument */"), "{}", out);
+        }
+    }
+
+    #[test]
+    fn wrap_comment_continues_with_stars() {
+        let comment = Doc::wrap_comment("one two three four five six");
+        assert_eq!(comment.render(80), Doc::block_comment("one two three four five six").render(80));
+        assert_eq!(comment.render(12), "/* one two\n * three\n * four five\n * six */");
+        let nested = Doc::from("x = ").concat(comment);
+        assert_eq!(nested.render(16), "x = /* one two\n     * three\n     * four five\n     * six */");
+    }
+
+    // The sum of the squares of the room left on every line but the last.
+    fn raggedness(text : &str, width : usize) -> usize {
+        let lines = text.lines().collect::<Vec<_>>();
+        lines[..lines.len() - 1].iter().map(|line| (width - display_width(line)).pow(2)).sum()
+    }
+
+    #[test]
+    fn optimal_line_breaks_are_less_ragged() {
+        let words = "aaa bb cc ddddd".split(' ').map(Doc::from).collect::<Vec<_>>();
+        let doc = fill(&words);
+        let mut options = RenderOptions::new(6);
+        let greedy = doc.render_with(&options);
+        assert_eq!(greedy, "aaa bb\ncc\nddddd");
+        options.line_break = LineBreak::Optimal;
+        let optimal = doc.render_with(&options);
+        assert_eq!(optimal, "aaa\nbb cc\nddddd");
+        assert!(raggedness(&optimal, 6) < raggedness(&greedy, 6));
+
+        let text = "the quick brown fox jumps over the lazy dog and keeps on running far away";
+        let words = text.split(' ').map(Doc::from).collect::<Vec<_>>();
+        let doc = Doc::from("> ").concat(fill_sep(&words, Doc::nil()).align());
+        for width in [16, 20, 25, 30] {
+            options.line_width = width;
+            let optimal = doc.render_with(&options);
+            options.line_break = LineBreak::Greedy;
+            let greedy = doc.render_with(&options);
+            options.line_break = LineBreak::Optimal;
+            assert!(optimal.lines().all(|line| display_width(line) <= width), "{}", optimal);
+            assert_eq!(optimal.split_whitespace().filter(|w| *w != ">").collect::<Vec<_>>().join(" "), text);
+            assert!(raggedness(&optimal, width) <= raggedness(&greedy, width));
+        }
+        options.line_width = 80;
+        assert_eq!(doc.render_with(&options), format!("> {}", text));
+    }
+
+    #[derive(Clone)]
+    struct Call {
+        name : &'static str,
+        args : Vec<&'static str>,
+    }
+
+    impl From<Call> for Doc {
+        fn from(call : Call) -> Doc {
+            let args = call.args.into_iter().map(Doc::from).collect::<Vec<_>>();
+            Doc::from(call.name).concat(enclose_sep(Doc::from("("), Doc::from(")"), Doc::from(", "), &args))
+        }
+    }
+
+    #[test]
+    fn types_that_convert_into_doc_are_pretty() {
+        let call = Call { name : "f", args : vec!["a", "b"] };
+        assert_eq!(call.pretty(80), Doc::from(call.clone()).render(80));
+        assert_eq!(call.pretty(80), "f(a, b)");
+        assert_eq!(42.pretty(80), "42");
+    }
+
+    #[test]
+    fn diamond_shared_subtrees_render_in_linear_time() {
+        // Each level uses the level below twice, so the tree is only a few
+        // dozen nodes but expands to 2^12 copies of "x y".
+        let mut doc = Doc::from("x").concat(Doc::newline()).concat("y").group();
+        for _ in 0..12 {
+            doc = doc.clone().concat(Doc::newline()).concat(doc).group();
+        }
+        let copies = 1 << 12;
+        assert_eq!(doc.render(usize::MAX).len(), 4 * copies - 1);
+        assert_eq!(doc.render(2).lines().count(), 2 * copies);
+        // Ten steps per copy (the same at any depth), whatever the
+        // width: no shared group is laid out more than once per position.
+        for width in [2, 80, usize::MAX] {
+            let mut options = RenderOptions::new(width);
+            options.max_nodes = Some(10 * copies);
+            assert!(doc.try_render_with(&options).is_ok(), "width {}", width);
+        }
+    }
+
+    #[test]
+    fn encodings_add_a_bom_and_round_trip() {
+        let doc = Doc::from("naïve").concat(Doc::hardline()).concat("ünïcödé");
+        let text = doc.render(80);
+        assert_eq!(doc.render_bytes(80, Encoding::Utf8), text.as_bytes());
+        let bom = doc.render_bytes(80, Encoding::Utf8Bom);
+        assert_eq!(bom[..3], [0xEF, 0xBB, 0xBF]);
+        assert_eq!(&bom[3..], text.as_bytes());
+
+        let le = doc.render_bytes(80, Encoding::Utf16Le);
+        assert_eq!(le[..2], [0xFF, 0xFE]);
+        let units = le[2..].chunks(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect::<Vec<_>>();
+        assert_eq!(String::from_utf16(&units).unwrap(), text);
+        let be = doc.render_bytes(80, Encoding::Utf16Be);
+        assert_eq!(be[..2], [0xFE, 0xFF]);
+        let units = be[2..].chunks(2).map(|c| u16::from_be_bytes([c[0], c[1]])).collect::<Vec<_>>();
+        assert_eq!(String::from_utf16(&units).unwrap(), text);
+
+        #[cfg(feature = "std")]
+        for encoding in [Encoding::Utf8, Encoding::Utf8Bom, Encoding::Utf16Le, Encoding::Utf16Be] {
+            let mut written = Vec::new();
+            doc.render_to_writer(80, encoding, &mut written).unwrap();
+            assert_eq!(written, doc.render_bytes(80, encoding));
+        }
+    }
+
+    #[test]
+    fn snap_to_tab_pads_to_the_next_stop() {
+        let after = |s : &str| Doc::from(s).concat(Doc::snap_to_tab(4)).concat("|").render(80);
+        assert_eq!(after("abcde"), "abcde   |");
+        assert_eq!(after("abcd"), "abcd|");
+        assert_eq!(after(""), "|");
+        let lines = Doc::from("ab").concat(Doc::snap_to_tab(4)).concat("x")
+                    .concat(Doc::hardline())
+                    .concat("abcdef").concat(Doc::snap_to_tab(4)).concat("x");
+        assert_eq!(lines.render(80), "ab  x\nabcdef  x");
+    }
+
+    #[test]
+    fn group_overrides_force_a_named_group() {
+        let args = Doc::softbreak().concat("a,").concat(Doc::line()).concat("b").nest(2);
+        let call = Doc::from("f(").concat(args).concat(Doc::softbreak()).concat(")").group_named("args");
+        let mut options = RenderOptions::new(80);
+        assert_eq!(call.render_with(&options), "f(a, b)");
+        options.group_overrides.insert("args", GroupMode::ForceBreak);
+        assert_eq!(call.render_with(&options), "f(\n  a,\n  b\n)");
+        options.line_width = usize::MAX;
+        assert_eq!(call.render_with(&options), "f(\n  a,\n  b\n)");
+
+        options.group_overrides.insert("args", GroupMode::ForceFlat);
+        options.line_width = 3;
+        assert_eq!(call.render_with(&options), "f(a, b)");
+        options.group_overrides.insert("args", GroupMode::Auto);
+        assert_eq!(call.render_with(&options), call.render(3));
+        assert_eq!(call.group().render_with(&options), call.render(3));
+    }
+
+    #[test]
+    fn degenerate_documents_render_as_documented() {
+        let blank_in_block = Doc::from("a").concat(Doc::newline()).concat(Doc::newline()).concat("b").nest(2);
+        let cases : [(&str, Doc, usize, &str); 12] = [
+            ("nil",                      Doc::nil(),                            80, ""),
+            ("nested nil",               Doc::nil().nest(4),                    80, ""),
+            ("empty text",               Doc::text(String::new()),              80, ""),
+            ("nested empty text",        Doc::text(String::new()).nest(4),      0,  ""),
+            ("newline",                  Doc::newline(),                        80, "\n"),
+            ("nested newline",           Doc::newline().nest(4),                80, "\n"),
+            ("nested newline_zero",      Doc::newline_zero().nest(4),           80, "\n"),
+            ("nested hardline",          Doc::hardline().nest(4),               80, "\n"),
+            ("blank line in a block",    blank_in_block,                        80, "a\n\n  b"),
+            ("grouped newline, fits",    Doc::newline().group(),                80, " "),
+            ("grouped newline, doesn't", Doc::newline().group(),                0,  "\n"),
+            ("grouped newline_zero",     Doc::newline_zero().group(),           80, ""),
+        ];
+        for (name, doc, width, expected) in cases.iter() {
+            assert_eq!(doc.render(*width), *expected, "{}", name);
+        }
+        // Spaces the document asks for itself stay, even at the end of a line.
+        assert_eq!(Doc::from("a ").concat(Doc::hardline()).concat("b").render(80), "a \nb");
+    }
+
+    #[test]
+    fn node_budget_stops_the_render() {
+        let doc = (0..1000).fold(Doc::nil(), |acc, _| acc.concat("x").group());
+        let mut options = RenderOptions::new(80);
+        options.max_nodes = Some(100);
+        assert_eq!(doc.try_render_with(&options), Err(RenderLimit { max_nodes : 100 }));
+        options.max_nodes = Some(1_000_000);
+        assert_eq!(doc.try_render_with(&options), Ok(doc.render(80)));
+    }
+
+    #[test]
+    fn tab_to_lines_up_with_a_tab_set_above() {
+        let row = |key : &str, value : &str, first : bool| {
+            let stop = if first { Doc::set_tab("val") } else { Doc::tab_to("val") };
+            Doc::from(key).concat(" = ").concat(stop).concat(value)
+        };
+        let doc = row("a_long_key", "1", true).concat(Doc::hardline()).concat(row("k", "2", false));
+        assert_eq!(doc.render(80), "a_long_key = 1\nk =          2");
+        assert_eq!(Doc::from("x").concat(Doc::tab_to("unset")).concat("y").render(80), "xy");
+    }
+
+    #[test]
+    fn trailing_content_can_be_left_to_a_later_group() {
+        let args = |items : &[&str]| {
+            let body = items.iter().map(|s| Doc::from(*s)).reduce(|a, b| a.concat(",").concat(Doc::newline()).concat(b)).unwrap();
+            Doc::from("(").concat(Doc::softbreak().concat(body).nest(2)).concat(Doc::softbreak()).concat(")").group()
+        };
+        let doc = Doc::from("foo").concat(args(&["a", "b"])).concat(".then_with_long_name").concat(args(&["x"]));
+        let mut options = RenderOptions::new(20);
+        assert_eq!(doc.render_with(&options), "foo(\n  a,\n  b\n).then_with_long_name(\n  x\n)");
+        options.trailing_content_forces_break = false;
+        assert_eq!(doc.render_with(&options), "foo(a, b).then_with_long_name(\n  x\n)");
+    }
+
+    #[test]
+    fn pages_do_not_split_keep_together_blocks() {
+        let lines = |range : core::ops::Range<usize>| {
+            range.map(|i| Doc::text(format!("line {}", i))).reduce(|a, b| a.concat(Doc::hardline()).concat(b)).unwrap()
+        };
+        let pages = lines(0..25).render_paginated(80, 10);
+        assert_eq!(pages.len(), 3);
+        assert_eq!(pages.iter().map(|page| page.lines().count()).collect::<Vec<_>>(), [10, 10, 5]);
+        assert_eq!(pages.join("\n"), lines(0..25).render(80));
+
+        // Lines 8..12 would straddle the first page break.
+        let doc = lines(0..8).concat(Doc::hardline())
+                  .concat(Doc::keep_together(lines(8..12))).concat(Doc::hardline())
+                  .concat(lines(12..25));
+        let pages = doc.render_paginated(80, 10);
+        assert_eq!(pages.iter().map(|page| page.lines().count()).collect::<Vec<_>>(), [8, 10, 7]);
+        assert!(pages[1].starts_with("line 8\nline 9\nline 10\nline 11\n"));
+    }
+
+    #[test]
+    fn with_width_lays_a_subtree_out_wider() {
+        let words = |s : &str| fill(&s.split(' ').map(Doc::from).collect::<Vec<_>>());
+        let prose = words("some prose that is long enough to wrap at eighty columns once it has gone on for a while");
+        let code = words("let value = compute(first_argument, second_argument, third_argument, fourth_argument);");
+        let doc = prose.clone().concat(Doc::hardline()).concat(Doc::with_width(120, code.clone()));
+        let out = doc.render(80);
+        let lines = out.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].len() <= 80 && lines[2].len() > 80, "{}", out);
+        assert_eq!(lines[2], code.render(120));
+        assert_eq!(Doc::with_width(40, code.clone()).render(80), code.render(40));
+    }
+
+    #[test]
+    fn indent_fn_writes_a_line_number_gutter() {
+        let mut options = RenderOptions::new(80);
+        options.set_indent_fn(|nest, line| {
+            let number = format!("{} ", line + 1);
+            let pad = nest.saturating_sub(number.len());
+            format!("{}{}", number, " ".repeat(pad))
+        });
+        let doc = Doc::from("fn f() {")
+                  .concat(Doc::hardline().concat("body();").concat(Doc::hardline()).concat("more();").nest(4))
+                  .concat(Doc::hardline()).concat("}");
+        assert_eq!(doc.render_with(&options), "1 fn f() {\n2   body();\n3   more();\n4 }");
+    }
+
+    #[test]
+    fn render_cow_borrows_a_lone_text() {
+        assert!(matches!(Doc::text(String::from("hi")).render_cow(80), Cow::Borrowed("hi")));
+        assert!(matches!(Doc::from("hi").nest(2).group().render_cow(80), Cow::Borrowed("hi")));
+        assert!(matches!(Doc::nil().render_cow(80), Cow::Borrowed("")));
+        let two = Doc::from("hi").concat_space("there");
+        match two.render_cow(80) {
+            Cow::Owned(s) => assert_eq!(s, "hi there"),
+            Cow::Borrowed(s) => panic!("borrowed {:?}", s),
+        }
+    }
+
+    #[test]
+    fn align_decimal_lines_up_the_points() {
+        let column = |numbers : &[&str]| align_decimal(&numbers.iter().map(|n| Doc::from(*n)).collect::<Vec<_>>()).render(80);
+        assert_eq!(column(&["3.14", "12.5", "100"]), "  3.14\n 12.5 \n100   ");
+        assert_eq!(column(&["-1.5", "2", "-10.25"]), " -1.5 \n  2   \n-10.25");
+        let numbers = [Doc::from("1").concat(Doc::softbreak()).concat(".5"), Doc::from("10")];
+        let column = align_decimal(&numbers);
+        assert_eq!(column.render(80), " 1.5\n10  ");
+        assert_eq!(Doc::from("x = ").concat(column.align()).group().render(80), "x =  1.5\n    10  ");
+    }
+
+    #[test]
+    #[should_panic(expected = "hard line break")]
+    fn align_decimal_rejects_multi_line_numbers() {
+        align_decimal(&[Doc::from("1").concat(Doc::hardline()).concat("2")]);
+    }
+
+    #[test]
+    fn render_lines_map_can_number_non_blank_lines() {
+        let doc = Doc::from("one").concat(Doc::user_blank_line()).concat("two").concat(Doc::hardline()).concat("three");
+        let mut number = 0;
+        let out = doc.render_lines_map(80, |_, line| {
+            if line.is_empty() {
+                line
+            } else {
+                number += 1;
+                format!("{}: {}", number, line)
+            }
+        });
+        assert_eq!(out, "1: one\n\n2: two\n3: three");
+        let mut indices = Vec::new();
+        assert_eq!(doc.render_lines_map(80, |i, line| { indices.push(i); line }), doc.render(80));
+        assert_eq!(indices, [0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn minimal_diff_keeps_crlf_line_endings() {
+        let doc = Doc::from("fn main() {").concat(Doc::hardline().concat("run();").nest(4)).concat(Doc::hardline()).concat("}");
+        let previous = "fn main() {\r\n    run();  \r\n}\r\n";
+        assert_eq!(doc.render_minimal_diff(80, previous), "fn main() {\r\n    run();  \r\n}");
+        let changed = "fn main() {\r\n    walk();\r\n}";
+        assert_eq!(doc.render_minimal_diff(80, changed), "fn main() {\r\n    run();\n}");
+        assert_eq!(doc.render_minimal_diff(80, ""), doc.render(80));
+    }
+
+    #[test]
+    fn group_first_line_stays_inline_up_to_its_first_break() {
+        let body = "aaa bbb ccc ddd eee".split(' ').map(Doc::from).reduce(Doc::concat_newline).unwrap();
+        let prefixed = |group : Doc| Doc::from("xs: ").concat(group);
+        // At width 12 the first line fits, the whole group doesn't.
+        assert_eq!(prefixed(body.group()).render(12), "xs: aaa\nbbb\nccc\nddd\neee");
+        assert_eq!(prefixed(body.group_first_line()).render(12), "xs: aaa bbb\nccc ddd eee");
+        assert_eq!(prefixed(body.group_first_line()).render(80), prefixed(body.group()).render(80));
+        // Not even "xs: aaa" fits, so it breaks as a normal group would.
+        assert_eq!(prefixed(body.group_first_line()).render(3), prefixed(body.group()).render(3));
+    }
+
+    #[test]
+    fn continued_lines_end_in_a_backslash() {
+        let words = "docker run --rm --interactive --tty --volume /src:/src image:latest make all".split(' ').map(Doc::from).collect::<Vec<_>>();
+        let command = Doc::continued(fill(&words).nest(2));
+        let out = command.render(30);
+        assert_eq!(out, "docker run --rm \\\n  --interactive --tty \\\n  --volume /src:/src \\\n  image:latest make all");
+        assert!(out.lines().all(|line| line.len() <= 30));
+        assert_eq!(command.render(200), words.iter().map(|w| w.render(80)).collect::<Vec<_>>().join(" "));
+        let separate = Doc::continued(Doc::from("a").concat(Doc::hardline()).concat("b"));
+        assert_eq!(separate.render(80), "a\nb");
+    }
+
+    #[test]
+    fn ribbon_fraction_limits_content_per_line() {
+        let words = |n : usize| (0..n).map(|_| Doc::from("word")).reduce(Doc::concat_newline).unwrap().group();
+        let mut options = RenderOptions::new(80);
+        options.ribbon_fraction = 0.5;
+        assert_eq!(options.ribbon_width(80), 40);
+        // 8 words are 39 columns, 9 are 44.
+        assert_eq!(words(8).render_with(&options).lines().count(), 1);
+        assert_eq!(words(9).render_with(&options).lines().count(), 9);
+        assert_eq!(words(9).render(80).lines().count(), 1);
+        // Indentation doesn't count against the ribbon.
+        let indented = Doc::from("x").concat(Doc::hardline().concat(words(8)).nest(30));
+        assert_eq!(indented.render_with(&options).lines().count(), 2);
+
+        options.ribbon_fraction = 0.0;
+        assert_eq!(options.ribbon_width(80), 1);
+        options.ribbon_fraction = 2.0;
+        assert_eq!(options.ribbon_width(80), 80);
+    }
+
+    #[test]
+    fn render_boxed_draws_a_rectangle() {
+        let doc = Doc::from("short").concat(Doc::hardline()).concat("a longer line");
+        assert_eq!(doc.render_boxed(80, '#'), "#################\n# short         #\n# a longer line #\n#################");
+        let boxed = doc.render_boxed(80, '*');
+        assert!(boxed.lines().all(|line| display_width(line) == 17 && line.ends_with('*')));
+    }
+
+    #[test]
+    fn user_blank_line_is_one_blank_line_even_when_flat() {
+        let doc = Doc::from("a").concat(Doc::user_blank_line()).concat("b");
+        assert_eq!(doc.render(80), "a\n\nb");
+        assert_eq!(doc.clone().group().render(80), "a\n\nb");
+        assert_eq!(Doc::from("{").concat(Doc::hardline().concat(doc).nest(2)).render(80), "{\n  a\n\n  b");
+    }
+
+    #[test]
+    fn render_trace_records_each_group_decision() {
+        let inner = Doc::from("b").concat(Doc::newline()).concat("c").group_named("inner");
+        let doc = Doc::from("a").concat(Doc::newline()).concat(inner).nest(2).group();
+        let decision = |name, column, flat_width, available, broke| GroupDecision { name, column, flat_width, available, broke };
+        assert_eq!(doc.render_trace(80), (String::from("a b c"), vec![
+            decision(None, 0, 5, 80, false),
+            decision(Some("inner"), 2, 3, 78, false),
+        ]));
+        assert_eq!(doc.render_trace(4), (String::from("a\n  b\n  c"), vec![
+            decision(None, 0, 5, 4, true),
+            decision(Some("inner"), 2, 3, 2, true),
+        ]));
+    }
+
+    #[test]
+    fn min_width_for_lines_finds_the_threshold() {
+        let text = "the quick brown fox jumps over the lazy dog and then keeps on running far far away";
+        let doc = fill(&text.split(' ').map(Doc::from).collect::<Vec<_>>());
+        assert_eq!((doc.count_lines(20), doc.count_lines(80)), (5, 2));
+        assert_eq!(doc.min_width_for_lines(3), Some(29));
+        assert_eq!(doc.count_lines(29), 3);
+        assert_eq!(doc.count_lines(28), 4);
+        assert_eq!(doc.min_width_for_lines(1), Some(text.len()));
+        assert_eq!(Doc::from("a").concat(Doc::hardline()).concat("b").min_width_for_lines(1), None);
+    }
+
+    #[test]
+    fn align_lines_arms_up_under_the_scrutinee() {
+        let arms = Doc::from("value").concat(Doc::hardline()).concat("| Some(x) => x")
+                   .concat(Doc::hardline()).concat("| None => 0");
+        let doc = Doc::from("let y = match ").concat(arms.clone().align());
+        assert_eq!(doc.render(80), "let y = match value\n              | Some(x) => x\n              | None => 0");
+        let hung = Doc::from("match ").concat(arms.hang(2));
+        assert_eq!(hung.render(80), "match value\n        | Some(x) => x\n        | None => 0");
+        // Relative to the column, not to the enclosing nest.
+        let nested = Doc::from("f(").concat(Doc::from("a").concat(Doc::hardline()).concat("b").align()).nest(8);
+        assert_eq!(nested.render(80), "f(a\n  b");
+    }
+
+    #[test]
+    fn no_line_ends_in_indentation() {
+        let block = |body : Doc| Doc::from("{").concat(Doc::newline().concat(body).nest(4)).concat(Doc::newline()).concat("}");
+        let statements = Doc::from("a();").concat(Doc::newline()).concat(Doc::newline())
+                         .concat(Doc::user_blank_line()).concat("b();");
+        let doc = block(block(statements).concat(Doc::newline()).concat(Doc::nil().nest(8)).concat(Doc::newline()).concat("c();"));
+        let out = doc.render(10);
+        assert_eq!(out.lines().count(), 11);
+        for line in out.lines() {
+            assert_eq!(line, line.trim_end(), "trailing whitespace in {:?}", out);
+        }
+    }
+
+    #[test]
+    fn render_checked_reports_overflowing_lines() {
+        let doc = Doc::from("short").concat(Doc::hardline()).concat("an_unbreakable_identifier")
+                  .concat(Doc::hardline()).concat("ok").concat(Doc::hardline()).concat("another_long_one");
+        let (out, overflows) = doc.render_checked(10);
+        assert_eq!(out, doc.render(10));
+        assert_eq!(overflows, [Overflow { line : 1, width : 25 }, Overflow { line : 3, width : 16 }]);
+        assert_eq!(doc.render_checked(25).1, []);
+    }
+
+    #[test]
+    fn flatten_renders_the_same_at_any_width() {
+        let items = ["first", "second", "third"].iter().map(|s| Doc::from(*s)).collect::<Vec<_>>();
+        let doc = Doc::from("call(").concat(Doc::newline_zero().concat(join(&items, &Doc::from(",").concat(Doc::newline()))).nest(2))
+                  .concat(Doc::newline_zero()).concat(")").group();
+        let flat = doc.flatten();
+        for width in [0, 1, 10, 80, usize::MAX] {
+            assert_eq!(flat.render(width), "call(first, second, third)");
+        }
+        assert_eq!(flat.render(0), doc.render(usize::MAX));
+    }
+
+    // A `fmt::Write` that gives up once it has `limit` bytes, to look at
+    // the start of output too long to ever finish.
+    struct Head {
+        text : String,
+        limit : usize,
+    }
+
+    impl core::fmt::Write for Head {
+        fn write_str(&mut self, s : &str) -> core::fmt::Result {
+            self.text.push_str(s);
+            if self.text.len() >= self.limit { Err(core::fmt::Error) } else { Ok(()) }
+        }
+    }
+
+    #[test]
+    fn huge_flat_lengths_saturate() {
+        // Shared, so 64 doublings are 64 nodes, but 4 * 2^64 columns wide.
+        let mut huge = Doc::from("xxxx");
+        for _ in 0..64 {
+            huge = huge.clone().concat(huge);
+        }
+        assert_eq!(huge.get_flat_len(), usize::MAX);
+        assert_eq!(huge.get_dist_newline(), usize::MAX);
+        let doc = Doc::from("a").concat(Doc::newline()).concat("b").group().concat(huge.clone());
+        assert_eq!(doc.get_flat_len(), usize::MAX);
+        assert_eq!(doc.group().get_flat_len(), usize::MAX);
+        // The group can never fit in front of that, so it breaks.
+        let mut head = Head { text : String::new(), limit : 8 };
+        assert!(doc.render_to(usize::MAX - 1, &mut head).is_err());
+        assert!(head.text.starts_with("a\nbxxxx"), "{:?}", head.text);
+    }
+
+    #[test]
+    fn dropping_a_million_node_chain_does_not_overflow() {
+        let left = (0..1_000_000).fold(Doc::nil(), |acc, _| acc.concat(Doc::from("x")));
+        drop(left);
+        let right = (0..1_000_000).fold(Doc::nil(), |acc, _| Doc::from("x").concat(acc).nest(1));
+        drop(right);
+    }
+
+    #[test]
+    fn indent_lays_out_a_nested_struct_literal() {
+        fn literal(name : &str, fields : Vec<Doc>) -> Doc {
+            let fields = join(&fields, &Doc::from(",").concat(Doc::newline()));
+            Doc::text(format!("{} {{", name)).concat(fields.indent(2)).concat(Doc::newline()).concat("}").group()
+        }
+        let inner = literal("Point", vec![Doc::from("x: 1"), Doc::from("y: 2")]);
+        let outer = literal("Line", vec![Doc::from("start: ").concat(inner.clone()), Doc::from("end: ").concat(inner)]);
+        assert_eq!(outer.render(80), "Line { start: Point { x: 1, y: 2 }, end: Point { x: 1, y: 2 } }");
+        assert_eq!(outer.render(40), "Line {\n  start: Point { x: 1, y: 2 },\n  end: Point { x: 1, y: 2 }\n}");
+        assert_eq!(outer.render(20), "Line {\n  start: Point {\n    x: 1,\n    y: 2\n  },\n  end: Point {\n    x: 1,\n    y: 2\n  }\n}");
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn interned_copies_share_one_allocation() {
+        let build = || Doc::text(String::from("let")).concat_space(Doc::text(String::from("x"))).group();
+        let (first, second) = (build(), build());
+        assert!(!Arc::ptr_eq(&first.0, &second.0));
+        let mut interner = DocInterner::new();
+        let first = interner.intern(&first);
+        let second = interner.intern(&second);
+        assert!(Arc::ptr_eq(&first.0, &second.0));
+        assert_eq!(first.render(80), "let x");
+        let count = interner.len();
+        let bigger = interner.intern(&build().concat(";"));
+        assert_eq!(interner.len(), count + 2);
+        match bigger.as_ref() {
+            Concat { lhs, .. } => assert!(Arc::ptr_eq(&lhs.0, &first.0)),
+            _ => panic!("expected a concat"),
+        }
+    }
+
+    #[test]
+    fn bracket_helpers_match_surround() {
+        let body = || Doc::from("a").concat(Doc::newline()).concat("b").group();
+        for (helper, open, close) in [(Doc::surround_paren as fn(Doc) -> Doc, "(", ")"),
+                                      (Doc::surround_curly, "{", "}"),
+                                      (Doc::surround_square, "[", "]")] {
+            let expected = Doc::from(open).concat(body()).concat(Doc::from(close));
+            assert!(helper(body()).structurally_eq(&expected));
+            assert!(body().surround(open, close).structurally_eq(&expected));
+            assert_eq!(helper(body()).render(80), format!("{}a b{}", open, close));
+        }
+        assert_eq!(Doc::from("T").surround("«", "»").render(80), "«T»");
+        assert_eq!(Doc::from("x").surround(Doc::from("```").concat(Doc::hardline()), Doc::hardline().concat("```")).render(80), "```\nx\n```");
+    }
+
+    #[test]
+    fn fit_is_measured_in_columns_not_bytes() {
+        let words = |s : &str| s.split(' ').map(Doc::from).reduce(Doc::concat_newline).unwrap().group();
+        // 11 columns, 13 bytes.
+        assert_eq!(words("héllo wörld").render(11), "héllo wörld");
+        assert_eq!(words("héllo wörld").render(10), "héllo\nwörld");
+        // Multibyte text earlier in the output, on a previous line and on
+        // this one, doesn't count against the line either.
+        let doc = Doc::from("ééééé").concat(Doc::hardline()).concat("ü ").concat(words("a b"));
+        assert_eq!(doc.render(5), "ééééé\nü a b");
+        // Wide characters take two columns.
+        assert_eq!(words("日本 語").render(6), "日本\n語");
+        assert_eq!(words("日本 語").render(7), "日本 語");
+    }
+
+    #[test]
+    fn concat_with_nil_adds_no_node() {
+        let x = Doc::from("x").concat(Doc::newline()).concat("y").group();
+        for doc in [Doc::nil().concat(x.clone()), x.clone().concat(Doc::nil()), Doc::nil().concat(x.clone()).concat(Doc::nil())] {
+            assert!(Arc::ptr_eq(&doc.0, &x.0));
+            assert_eq!(doc.get_flat_len(), x.get_flat_len());
+            assert_eq!(doc.render(1), x.render(1));
+        }
+        let seeded = ["a", "b", "c"].iter().fold(Doc::nil(), |acc, s| acc.concat(*s));
+        let unseeded = Doc::from("a").concat("b").concat("c");
+        assert!(seeded.structurally_eq(&unseeded));
+        assert_eq!(node_count(&seeded), node_count(&unseeded));
+        assert_eq!(seeded.get_flat_len(), 3);
+    }
+
+    #[test]
+    fn left_out_pieces_contribute_nothing() {
+        let item = |public : bool, ret : Option<Doc>| {
+            Doc::when(public, "pub ").concat("fn f()").concat(Doc::from_option(ret.map(|ret| Doc::from(" -> ").concat(ret))))
+        };
+        assert_eq!(item(true, Some(Doc::from("u8"))).render(80), "pub fn f() -> u8");
+        let bare = item(false, None);
+        assert_eq!(bare.render(80), "fn f()");
+        assert_eq!(bare.get_flat_len(), "fn f()".len());
+        assert!(bare.structurally_eq(&Doc::from("fn f()")));
+        assert_eq!(Doc::when(false, "x").get_flat_len(), 0);
+        assert!(Doc::from_option(None).structurally_eq(&Doc::nil()));
+    }
+
+    #[test]
+    fn group_align_lines_arguments_up_under_the_first() {
+        let args = ["aaaa", "bbbb", "cccc"].iter().map(|s| Doc::from(*s)).collect::<Vec<_>>();
+        let call = Doc::from("foo(").concat(join(&args, &Doc::from(",").concat(Doc::newline())).group_align()).concat(")");
+        assert_eq!(call.render(80), "foo(aaaa, bbbb, cccc)");
+        assert_eq!(call.render(12), "foo(aaaa,\n    bbbb,\n    cccc)");
+        let nested = Doc::from("let x =").concat(Doc::hardline().concat(call).nest(2));
+        assert_eq!(nested.render(12), "let x =\n  foo(aaaa,\n      bbbb,\n      cccc)");
+    }
+
+    #[test]
+    fn concat_line_zero_flattens_without_a_space() {
+        let doc = Doc::from("[").concat(Doc::from("foo,").concat_line_zero("bar").nest(2)).concat("]").group();
+        assert_eq!(doc.render(80), "[foo,bar]");
+        assert_eq!(doc.render(5), "[foo,\n  bar]");
+        assert_eq!(Doc::from("foo,").concat_newline("bar").group().render(80), "foo, bar");
+    }
+
+    #[test]
+    fn blank_line_collapsing_keeps_user_blank_lines() {
+        let doc = Doc::from("a").concat(Doc::user_blank_line()).concat(Doc::hardline()).concat(Doc::hardline()).concat("b");
+        assert_eq!(doc.render(80), "a\n\n\n\nb");
+        let mut options = RenderOptions::new(80);
+        options.max_consecutive_blank_lines = Some(0);
+        assert_eq!(doc.render_with(&options), "a\n\nb");
+        let incidental = Doc::from("a").concat(Doc::hardline()).concat(Doc::hardline()).concat(Doc::hardline()).concat("b");
+        assert_eq!(incidental.render_with(&options), "a\nb");
+        options.max_consecutive_blank_lines = Some(1);
+        assert_eq!(incidental.render_with(&options), "a\n\nb");
+        assert_eq!(doc.render_with(&options), "a\n\nb");
+        let grouped = Doc::from("x").concat(Doc::user_blank_line()).concat(Doc::newline()).concat("y").group();
+        assert_eq!(grouped.render(80), "x\n\n\ny");
+        options.max_consecutive_blank_lines = Some(0);
+        assert_eq!(grouped.render_with(&options), "x\n\ny");
+    }
+
+    #[test]
+    fn fits_within_agrees_with_fits_flat() {
+        let words = ["alpha", "beta", "gamma", "delta"].iter().map(|w| Doc::from(*w)).collect::<Vec<_>>();
+        let right = words.iter().rev().fold(Doc::nil(), |acc, w| w.clone().concat(Doc::line()).concat(acc));
+        let docs = [
+            sep(&words),
+            right.clone(),
+            right.clone().nest(2).group().annotate(()),
+            Doc::from("a").concat(Doc::hardline()).concat("b"),
+            Doc::from("x").concat(Doc::user_blank_line()),
+            Doc::nil(),
+        ];
+        for doc in docs.iter() {
+            for budget in [0, 3, 10, 22, 23, 24, 80, usize::MAX] {
+                assert_eq!(doc.fits_within(budget), doc.fits_flat(budget), "{:?} at {}", doc.render_flat(), budget);
+            }
+        }
+    }
+
+    #[test]
+    fn doc_builder_builds_a_balanced_tree() {
+        let pieces = (0..1000).map(|i| if i % 7 == 6 { Doc::line() } else { Doc::from(i) }).collect::<Vec<_>>();
+        let mut builder = DocBuilder::with_capacity(pieces.len());
+        for piece in pieces.iter() {
+            builder.push(piece.clone());
+        }
+        assert_eq!(builder.len(), 1000);
+        let built = builder.finish();
+        let chain = sep(&pieces);
+        assert_eq!(built.get_flat_len(), chain.get_flat_len());
+        assert_eq!(built.get_dist_newline(), chain.get_dist_newline());
+        assert_eq!(built.get_has_newline(), chain.get_has_newline());
+        for width in [10, 80] {
+            assert_eq!(built.render(width), chain.render(width));
+            assert_eq!(built.clone().group().render(width), chain.clone().group().render(width));
+        }
+        fn depth(doc : &Doc) -> usize {
+            match doc.as_ref() {
+                Concat { lhs, rhs, .. } => 1 + depth(lhs).max(depth(rhs)),
+                _                       => 0,
+            }
+        }
+        assert_eq!(depth(&built), 10);
+        assert!(DocBuilder::<()>::new().finish().structurally_eq(&Doc::nil()));
+    }
+
+    #[test]
+    fn deeply_right_nested_concats_render() {
+        let mut doc = Doc::from("x");
+        for _ in 0..DEEP {
+            doc = Doc::from("y").concat(Doc::newline()).concat(doc).group();
+        }
+        let out = doc.render(80);
+        assert_eq!(out.len(), 2 * DEEP + 1);
+        assert_eq!(doc.flatten().render(80), doc.render_flat());
+        assert!(doc.structurally_eq(&doc.map_text(|s| s.to_string())));
+    }
+}
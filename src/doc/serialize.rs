@@ -0,0 +1,217 @@
+// `Serialize` and `Deserialize` for documents, with the `serde` feature.
+// Only what each node was built from is written out, not the
+// measurements cached in it: reading a document back puts it together
+// again with the usual constructors, which work those out afresh, so they
+// can't disagree with the tree. Sharing isn't kept; a subtree that's in
+// several places is written out, and read back, once for each.
+//
+// A node that holds a function (`column`, `nesting`, `with_line_count`)
+// can't be written out, so serializing a document with one in it fails
+// with the serializer's error rather than leaving it out.
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use serde::de::Error as _;
+use serde::ser::Error as _;
+use serde::{ Deserialize, Deserializer, Serialize, Serializer };
+
+use super::*;
+use crate::placeholder::PlaceholderId;
+
+// How a document is written out: one variant for each kind of node, with
+// the arguments of the constructor that makes it. A chain of `Concat`s is
+// one list of its pieces, so a long document isn't nested as deep as it
+// is long, and comes back as `balance` would leave it. The children are
+// `D`, the names and the text are `S` and the annotation is `N`: borrowed
+// when serializing, owned when deserializing.
+#[derive(Serialize, Deserialize)]
+#[serde(rename = "Doc")]
+enum Repr<D, S, N> {
+    Nil,
+    Newline,
+    NewlineZero,
+    Text(S),
+    Concat(Vec<D>),
+    Nest { nest : isize, doc : D },
+    Group { name : Option<S>, first_line : bool, doc : D },
+    Placeholder { id : usize, doc : D },
+    WithWidth { width : usize, doc : D },
+    KeepTogether(D),
+    TabStop { width : usize },
+    SetTab { name : S },
+    TabTo { name : S },
+    Continued(D),
+    Fold { id : usize, summary : D, body : D },
+    UserBlankLine,
+    Hardline,
+    RightSuffix(D),
+    LineSuffix(D),
+    Align { extra : usize, doc : D },
+    Fill(D),
+    Padded { width : usize, or_break : bool, doc : D },
+    Alt(Vec<D>),
+    Annotated { annotation : N, doc : D },
+}
+
+// The pieces of the chain of `Concat`s at the top of `doc`, in order.
+fn pieces<A : 'static>(doc : &AnnotatedDoc<A>) -> Vec<&AnnotatedDoc<A>> {
+    let mut pieces = Vec::new();
+    let mut todo = vec![doc];
+    while let Some(doc) = todo.pop() {
+        match doc.as_ref() {
+            Concat { lhs, rhs, .. } => {
+                todo.push(rhs);
+                todo.push(lhs);
+            },
+            _ => pieces.push(doc),
+        }
+    }
+    pieces
+}
+
+// Group and tab names are `&'static str`s, so the ones read back are
+// leaked: they're kept until the program ends, not dropped with the
+// document.
+fn leak(name : String) -> &'static str {
+    Box::leak(name.into_boxed_str())
+}
+
+impl<A : Serialize + 'static> Serialize for AnnotatedDoc<A> {
+    fn serialize<S : Serializer>(&self, serializer : S) -> Result<S::Ok, S::Error> {
+        let repr : Repr<&AnnotatedDoc<A>, &str, &A> = match self.as_ref() {
+            Nil                                   => Repr::Nil,
+            Newline                               => Repr::Newline,
+            NewlineZero                           => Repr::NewlineZero,
+            Text { s, .. }                        => Repr::Text(s),
+            Concat { .. }                         => Repr::Concat(pieces(self)),
+            Nest { nest, doc, .. }                => Repr::Nest { nest : *nest, doc },
+            Group { name, first_line, doc, .. }   => Repr::Group { name : *name, first_line : *first_line, doc },
+            Placeholder { id, doc, .. }           => Repr::Placeholder { id : id.0, doc },
+            WithWidth { width, doc, .. }          => Repr::WithWidth { width : *width, doc },
+            KeepTogether { doc, .. }              => Repr::KeepTogether(doc),
+            TabStop { width }                     => Repr::TabStop { width : *width },
+            SetTab { name }                       => Repr::SetTab { name },
+            TabTo { name }                        => Repr::TabTo { name },
+            Continued { doc, .. }                 => Repr::Continued(doc),
+            Fold { id, summary, body, .. }        => Repr::Fold { id : id.0, summary, body },
+            UserBlankLine                         => Repr::UserBlankLine,
+            Hardline                              => Repr::Hardline,
+            RightSuffix { content }               => Repr::RightSuffix(content),
+            LineSuffix { doc }                    => Repr::LineSuffix(doc),
+            Align { extra, doc, .. }              => Repr::Align { extra : *extra, doc },
+            Fill { doc, .. }                      => Repr::Fill(doc),
+            Padded { width, or_break, doc, .. }   => Repr::Padded { width : *width, or_break : *or_break, doc },
+            Alt { options, .. }                   => Repr::Alt(options.iter().collect()),
+            Annotated { annotation, doc, .. }     => Repr::Annotated { annotation, doc },
+            Column { .. }
+            | Nesting { .. }
+            | LineCount { .. } => return Err(S::Error::custom("a document with a function in it (`column`, `nesting` or `with_line_count`) can't be serialized")),
+        };
+        repr.serialize(serializer)
+    }
+}
+
+impl<'de, A : Deserialize<'de> + 'static> Deserialize<'de> for AnnotatedDoc<A> {
+    fn deserialize<D : Deserializer<'de>>(deserializer : D) -> Result<Self, D::Error> {
+        let repr = Repr::<AnnotatedDoc<A>, String, A>::deserialize(deserializer)?;
+        Ok(match repr {
+            Repr::Nil                                => AnnotatedDoc::nil(),
+            Repr::Newline                            => AnnotatedDoc::newline(),
+            Repr::NewlineZero                        => AnnotatedDoc::newline_zero(),
+            Repr::Text(s) if s.contains('\n')        => return Err(D::Error::custom("a text atom with a newline in it")),
+            Repr::Text(s)                            => AnnotatedDoc::text(s),
+            Repr::Concat(pieces)                     => pieces.into_iter().fold(AnnotatedDoc::nil(), AnnotatedDoc::concat_owned),
+            Repr::Nest { nest, doc }                 => doc.nest_by(nest),
+            Repr::Group { name, first_line, doc }    => AnnotatedDoc::from(Group {
+                name : name.map(leak),
+                first_line,
+                has_newline : doc.get_has_newline(),
+                dist_newline : doc.get_dist_newline(),
+                flat_len : doc.get_flat_len(),
+                doc,
+            }),
+            Repr::Placeholder { id, doc }            => AnnotatedDoc::placeholder(PlaceholderId(id), doc),
+            Repr::WithWidth { width, doc }           => AnnotatedDoc::with_width(width, doc),
+            Repr::KeepTogether(doc)                  => AnnotatedDoc::keep_together(doc),
+            Repr::TabStop { width }                  => AnnotatedDoc::snap_to_tab(width),
+            Repr::SetTab { name }                    => AnnotatedDoc::set_tab(leak(name)),
+            Repr::TabTo { name }                     => AnnotatedDoc::tab_to(leak(name)),
+            Repr::Continued(doc)                     => AnnotatedDoc::continued(doc),
+            Repr::Fold { id, summary, body }         => AnnotatedDoc::foldable(FoldId(id), summary, body),
+            Repr::UserBlankLine                      => AnnotatedDoc::user_blank_line(),
+            Repr::Hardline                           => AnnotatedDoc::hardline(),
+            Repr::RightSuffix(content)               => AnnotatedDoc::right_align_suffix(content),
+            Repr::LineSuffix(doc)                    => doc.line_suffix(),
+            Repr::Align { extra, doc }               => doc.hang(extra),
+            Repr::Fill(doc)                          => AnnotatedDoc::from(Fill {
+                has_newline : doc.get_has_newline(),
+                dist_newline : doc.get_dist_newline(),
+                flat_len : doc.get_flat_len(),
+                doc,
+            }),
+            Repr::Padded { width, or_break, doc }    => doc.padded(width, or_break),
+            Repr::Alt(options)                       => AnnotatedDoc::alt(options),
+            Repr::Annotated { annotation, doc }      => doc.annotate(annotation),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip<A>(doc : &AnnotatedDoc<A>) -> AnnotatedDoc<A>
+    where A : Serialize + for<'de> Deserialize<'de> + 'static {
+        let json = serde_json::to_string(doc).expect("the document serializes");
+        serde_json::from_str(&json).expect("the document deserializes")
+    }
+
+    // `doc` with every chain of `Concat`s in it leaning left, which is
+    // how they come back.
+    fn lean_left<A : Clone + 'static>(doc : &AnnotatedDoc<A>) -> AnnotatedDoc<A> {
+        doc.rebuild(|_| None, true, AnnotatedDoc::concat_owned, DocFn::clone)
+    }
+
+    #[test]
+    fn documents_round_trip_through_json() {
+        let args = fill_sep(&["alpha", "beta", "gamma", "delta"].iter().map(|w| Doc::from(*w)).collect::<Vec<_>>(), Doc::from(","));
+        let call = Doc::from("call(").concat(Doc::softbreak().concat(args).nest(2)).concat(Doc::softbreak()).concat(")").group_named("call");
+        let doc = Doc::from("let").concat(Doc::set_tab("eq")).concat(" x").concat(Doc::tab_to("eq")).concat(" = ")
+                  .concat(call.align())
+                  .concat(Doc::line_suffix(Doc::from(" // note")))
+                  .concat(Doc::user_blank_line())
+                  .concat(Doc::foldable(FoldId(3), Doc::from("{ .. }"), key_value_block(&[(Doc::from("k"), Doc::from("v"))], 20)))
+                  .concat(Doc::hardline())
+                  .concat(Doc::alt(vec![Doc::from("short"), Doc::from("a").concat(Doc::newline()).concat("b")]))
+                  .concat(Doc::keep_together(Doc::from("x").fill_break(4).concat(Doc::snap_to_tab(8))))
+                  .concat(Doc::continued(Doc::from("p").concat(Doc::newline_zero()).concat("q").group_first_line()))
+                  .concat(Doc::right_align_suffix(Doc::from("#")))
+                  .concat(Doc::placeholder(PlaceholderId(1), Doc::from("slot")).dedent(1));
+        let back = round_trip(&doc);
+        assert!(back.structurally_eq(&lean_left(&doc)));
+        assert_eq!(back.get_flat_len(), doc.get_flat_len());
+        assert_eq!(back.get_dist_newline(), doc.get_dist_newline());
+        for width in [0, 10, 20, 40, 80] {
+            assert_eq!(back.render(width), doc.render(width));
+        }
+
+        // A chain built by prepending comes back leaning left.
+        let right = Doc::from("a").concat(Doc::line().concat(Doc::from("b").concat(Doc::line().concat("c"))));
+        assert!(!right.structurally_eq(&right.balance()));
+        assert!(round_trip(&right).structurally_eq(&right.balance()));
+
+        let styled = AnnotatedDoc::text(String::from("kw")).annotate(7u8).concat(" rest");
+        assert!(round_trip(&styled).structurally_eq(&styled));
+    }
+
+    #[test]
+    fn functions_fail_to_serialize() {
+        let doc = Doc::from("a").concat(Doc::column(Doc::from).group());
+        assert!(serde_json::to_string(&doc).is_err());
+        assert!(serde_json::to_string(&Doc::with_line_count(Doc::from, Doc::from("a"))).is_err());
+        assert!(serde_json::from_str::<Doc>(r#"{"Text":"a\nb"}"#).is_err());
+    }
+}
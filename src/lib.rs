@@ -1,2 +1,16 @@
+// Without the `std` feature (on by default) the crate only needs `alloc`;
+// all that's lost is `Doc::render_to_writer` and `DocInterner`, and the
+// maps in `RenderOptions` are ordered rather than hashed.
+//
+// The `serde` feature (off by default) makes documents `Serialize` and
+// `Deserialize`; see src/doc/serialize.rs.
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+mod macros;
+
+pub mod ansi;
 pub mod doc;
 pub mod parenable;
+pub mod placeholder;
@@ -0,0 +1,89 @@
+// A shorthand for building a document out of the usual constructors.
+// The annotation type is inferred, so the result can be an
+// `AnnotatedDoc<A>` as well as a plain `Doc`. Inside the braces:
+//
+// + A literal is a text atom (`"fn"`, `42`).
+// + `line` is a `Doc::newline()`, and `line_zero` a `Doc::newline_zero()`.
+// + `group( ... )` groups its contents, and `nest(n, ... )` nests them by
+//   `n`, which can be any expression.
+// + `#(expr)` splices in anything that converts into the document type,
+//   such as another document.
+// + Anything written one after another is concatenated, left to right.
+//
+// It builds exactly the tree the constructors would; e.g.
+//
+//   doc!{ group(nest(2, "fn" line "foo")) }
+//
+// is `Doc::from("fn").concat(Doc::newline()).concat(Doc::from("foo")).nest(2).group()`.
+#[macro_export]
+macro_rules! doc {
+    ($($body:tt)*) => {
+        $crate::__doc_seq!([] $($body)*)
+    };
+}
+
+// The workings of `doc!`: munches the input one item at a time, collecting
+// the documents made so far in the brackets, and concatenates them at the
+// end.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __doc_seq {
+    ([$($acc:expr),*]) => {
+        $crate::doc::sep::<_>(&[$($acc),*])
+    };
+    ([$($acc:expr),*] line_zero $($rest:tt)*) => {
+        $crate::__doc_seq!([$($acc,)* $crate::doc::AnnotatedDoc::newline_zero()] $($rest)*)
+    };
+    ([$($acc:expr),*] line $($rest:tt)*) => {
+        $crate::__doc_seq!([$($acc,)* $crate::doc::AnnotatedDoc::newline()] $($rest)*)
+    };
+    ([$($acc:expr),*] group ( $($inner:tt)* ) $($rest:tt)*) => {
+        $crate::__doc_seq!([$($acc,)* $crate::doc!($($inner)*).group()] $($rest)*)
+    };
+    ([$($acc:expr),*] nest ( $n:expr , $($inner:tt)* ) $($rest:tt)*) => {
+        $crate::__doc_seq!([$($acc,)* $crate::doc!($($inner)*).nest($n)] $($rest)*)
+    };
+    ([$($acc:expr),*] # ( $e:expr ) $($rest:tt)*) => {
+        $crate::__doc_seq!([$($acc,)* ::core::convert::Into::<$crate::doc::AnnotatedDoc<_>>::into($e)] $($rest)*)
+    };
+    ([$($acc:expr),*] $lit:literal $($rest:tt)*) => {
+        $crate::__doc_seq!([$($acc,)* $crate::doc::AnnotatedDoc::from($lit)] $($rest)*)
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::String;
+    use alloc::vec;
+
+    use crate::doc::{ AnnotatedDoc, Doc };
+
+    #[test]
+    fn doc_builds_the_same_tree_as_the_constructors() {
+        let by_hand = Doc::from("fn").concat(Doc::newline()).concat(Doc::from("foo")).nest(2).group();
+        let by_macro = doc!{ group(nest(2, "fn" line "foo")) };
+        assert!(by_macro.structurally_eq(&by_hand));
+
+        let args = Doc::from("a").concat(",").concat(Doc::newline()).concat("b");
+        let width = 4;
+        let by_hand = Doc::from("f(").concat(Doc::newline_zero()).concat(args.clone()).nest(width).group()
+                      .concat(Doc::from(")"));
+        let by_macro = doc!{ group(nest(width, "f(" line_zero #(args.clone()))) ")" };
+        assert!(by_macro.structurally_eq(&by_hand));
+        assert_eq!(by_macro.render(80), "f(a, b)");
+        assert_eq!(by_macro.render(4), "f(\n    a,\n    b)");
+
+        let empty : Doc = doc!{};
+        assert!(empty.structurally_eq(&Doc::nil()));
+        assert!(doc!{ 42 #("x") }.structurally_eq(&Doc::from(42).concat(Doc::from("x"))));
+    }
+
+    #[test]
+    fn doc_infers_a_non_unit_annotation() {
+        let name = AnnotatedDoc::text(String::from("foo")).annotate("ident");
+        let by_hand = AnnotatedDoc::from("fn").concat(AnnotatedDoc::newline()).concat(name.clone()).nest(2).group();
+        let by_macro : AnnotatedDoc<&str> = doc!{ group(nest(2, "fn" line #(name))) };
+        assert!(by_macro.structurally_eq(&by_hand));
+        assert_eq!(by_macro.render_with_spans(80), (String::from("fn foo"), vec![(3..6, "ident")]));
+    }
+}
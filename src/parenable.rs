@@ -1,36 +1,231 @@
-use crate::doc::Doc;
+use alloc::string::{ String, ToString };
+
+use crate::doc::{ AnnotatedDoc, Map };
 
 pub const MAX_PRIORITY : usize = 1024;
 
+// Which way a chain of the same infix operator groups, for
+// `Parenable::infix`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Assoc {
+    // `a - b - c` is `(a - b) - c`.
+    Left,
+    // `a ^ b ^ c` is `a ^ (b ^ c)`.
+    Right,
+    // Chaining without parentheses isn't allowed (`a == b == c`).
+    NonAssoc,
+}
+
 #[derive(Debug, Clone)]
-pub struct Parenable {
-    pub doc : Doc,
+pub struct Parenable<A : 'static = ()> {
+    pub doc : AnnotatedDoc<A>,
     pub priority : usize,
 }
 
-impl Parenable {
-    pub fn new(doc : Doc, priority : usize) -> Self {
+impl<A : 'static> Parenable<A> {
+    pub fn new(doc : AnnotatedDoc<A>, priority : usize) -> Self {
         Parenable {
             priority,
             doc
         }
     }
 
-    pub fn new_max(doc : Doc) -> Self {
+    pub fn new_max(doc : AnnotatedDoc<A>) -> Self {
         Parenable {
             priority : MAX_PRIORITY,
             doc
         }
     }
 
-    pub fn maybe_surround(&self, target_priority : usize) -> Doc {
+    pub fn maybe_surround(&self, target_priority : usize) -> AnnotatedDoc<A> {
         // If the given `Parenable`'s priority is less
         // than some given priority, surround with
         // parenthesis.
         if self.priority < target_priority {
-            Doc::from("(").concat(self.doc.clone()).concat(")")
+            self.doc.clone().surround_paren()
         } else {
             self.doc.clone()
         }
     }
-}
\ No newline at end of file
+
+    // `maybe_surround`, except that parentheses it adds can break: the
+    // whole thing is a group that's `(doc)` if it fits, and otherwise
+    //
+    //   (
+    //     doc
+    //   )
+    //
+    // with the contents nested by one unit of `RenderOptions::indent`.
+    // Without parentheses it's just the document, not grouped.
+    pub fn maybe_surround_group(&self, target_priority : usize) -> AnnotatedDoc<A> {
+        if self.priority < target_priority {
+            AnnotatedDoc::static_text("(")
+            .concat(AnnotatedDoc::softbreak().concat(self.doc.clone()).nest(1))
+            .concat(AnnotatedDoc::softbreak())
+            .concat(AnnotatedDoc::static_text(")"))
+            .group()
+        } else {
+            self.doc.clone()
+        }
+    }
+
+    // `self op rhs`, at `op_priority`. An operand is parenthesized if
+    // it binds more loosely than the operator, and also if it's at the
+    // same priority but on the side `assoc` doesn't group toward, so
+    // `infix` of `a` and `b - c` with a left-associative `-` comes out
+    // as `a - (b - c)`, while `a - b` and `c` gives `a - b - c`.
+    pub fn infix(self, op : &str, op_priority : usize, assoc : Assoc, rhs : Parenable<A>) -> Self {
+        let (lhs_target, rhs_target) = match assoc {
+            Assoc::Left     => (op_priority, op_priority.saturating_add(1)),
+            Assoc::Right    => (op_priority.saturating_add(1), op_priority),
+            Assoc::NonAssoc => (op_priority.saturating_add(1), op_priority.saturating_add(1)),
+        };
+        let doc = self.maybe_surround(lhs_target)
+                      .concat_space(AnnotatedDoc::text(op.to_string()))
+                      .concat_space(rhs.maybe_surround(rhs_target));
+        Parenable::new(doc, op_priority)
+    }
+
+    // `op` in front of `operand`, at `op_priority`, with the operand
+    // parenthesized if it binds more loosely than the operator: `-x`, but
+    // `-(x + y)`. A prefix operator applied to another one at the same
+    // priority doesn't need any parentheses. `op` is written as is, so
+    // include a space in it if one should follow it.
+    pub fn prefix(op : &str, op_priority : usize, operand : Parenable<A>) -> Self {
+        let doc = AnnotatedDoc::text(op.to_string()).concat(operand.maybe_surround(op_priority));
+        Parenable::new(doc, op_priority)
+    }
+
+    // `operand` followed by `op`, at `op_priority`, parenthesized as in
+    // `prefix`: `x!`, but `(a + b)!`.
+    pub fn postfix(operand : Parenable<A>, op : &str, op_priority : usize) -> Self {
+        let doc = operand.maybe_surround(op_priority).concat(AnnotatedDoc::text(op.to_string()));
+        Parenable::new(doc, op_priority)
+    }
+}
+
+// The operators of a language, registered once with their priorities (and
+// for infix ones, associativities), so that expressions can be built by
+// operator name instead of passing the numbers around. Prefix, infix and
+// postfix operators are kept apart, so e.g. `-` can be both a prefix and
+// an infix operator at different priorities.
+#[derive(Debug, Clone, Default)]
+pub struct PrecTable {
+    infix : Map<String, (usize, Assoc)>,
+    prefix : Map<String, usize>,
+    postfix : Map<String, usize>,
+}
+
+impl PrecTable {
+    pub fn new() -> Self {
+        PrecTable::default()
+    }
+
+    // Registering an operator again replaces what it was registered with.
+    pub fn add_infix(&mut self, op : &str, priority : usize, assoc : Assoc) -> &mut Self {
+        self.infix.insert(op.to_string(), (priority, assoc));
+        self
+    }
+
+    pub fn add_prefix(&mut self, op : &str, priority : usize) -> &mut Self {
+        self.prefix.insert(op.to_string(), priority);
+        self
+    }
+
+    pub fn add_postfix(&mut self, op : &str, priority : usize) -> &mut Self {
+        self.postfix.insert(op.to_string(), priority);
+        self
+    }
+
+    // `Parenable::infix` with `op`'s priority and associativity, or `None`
+    // if `op` isn't a registered infix operator.
+    pub fn binary<A : 'static>(&self, op : &str, lhs : Parenable<A>, rhs : Parenable<A>) -> Option<Parenable<A>> {
+        let (priority, assoc) = *self.infix.get(op)?;
+        Some(lhs.infix(op, priority, assoc, rhs))
+    }
+
+    // `Parenable::prefix` with `op`'s priority, or `None` if `op` isn't a
+    // registered prefix operator.
+    pub fn unary_prefix<A : 'static>(&self, op : &str, operand : Parenable<A>) -> Option<Parenable<A>> {
+        let priority = *self.prefix.get(op)?;
+        Some(Parenable::prefix(op, priority, operand))
+    }
+
+    // `Parenable::postfix` with `op`'s priority, or `None` if `op` isn't a
+    // registered postfix operator.
+    pub fn unary_postfix<A : 'static>(&self, operand : Parenable<A>, op : &str) -> Option<Parenable<A>> {
+        let priority = *self.postfix.get(op)?;
+        Some(Parenable::postfix(operand, op, priority))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::doc::{ Doc, Indent, RenderOptions };
+
+    const SUM : usize = 10;
+    const NEG : usize = 20;
+    const FACT : usize = 30;
+
+    fn atom(s : &'static str) -> Parenable {
+        Parenable::new_max(Doc::from(s))
+    }
+
+    fn sum(lhs : Parenable, rhs : Parenable) -> Parenable {
+        lhs.infix("+", SUM, Assoc::Left, rhs)
+    }
+
+    #[test]
+    fn prefix_and_postfix_parenthesize_looser_operands() {
+        assert_eq!(Parenable::prefix("-", NEG, atom("x")).doc.render(80), "-x");
+        assert_eq!(Parenable::prefix("-", NEG, sum(atom("x"), atom("y"))).doc.render(80), "-(x + y)");
+        let twice = Parenable::prefix("- ", NEG, Parenable::prefix("- ", NEG, atom("x")));
+        assert_eq!(twice.doc.render(80), "- - x");
+        assert_eq!(twice.priority, NEG);
+
+        assert_eq!(Parenable::postfix(atom("x"), "!", FACT).doc.render(80), "x!");
+        assert_eq!(Parenable::postfix(sum(atom("a"), atom("b")), "!", FACT).doc.render(80), "(a + b)!");
+        let twice = Parenable::postfix(Parenable::postfix(atom("n"), "!", FACT), "!", FACT);
+        assert_eq!(twice.doc.render(80), "n!!");
+        // A postfix operator binds tighter than the prefix one here.
+        assert_eq!(Parenable::prefix("-", NEG, Parenable::postfix(atom("n"), "!", FACT)).doc.render(80), "-n!");
+        assert_eq!(Parenable::postfix(Parenable::prefix("-", NEG, atom("n")), "!", FACT).doc.render(80), "(-n)!");
+    }
+
+    #[test]
+    fn maybe_surround_group_nests_by_one_indent_unit() {
+        let doc = sum(atom("x"), atom("y")).maybe_surround_group(NEG);
+        assert_eq!(doc.render(80), "(x + y)");
+        let mut options = RenderOptions::new(4);
+        options.indent = Indent::Spaces(4);
+        assert_eq!(doc.render_with(&options), "(\n    x + y\n)");
+        assert_eq!(sum(atom("x"), atom("y")).maybe_surround_group(SUM).render(4), "x + y");
+    }
+
+    #[test]
+    fn non_associative_operators_parenthesize_both_sides() {
+        let eq = |lhs : Parenable, rhs : Parenable| lhs.infix("==", SUM, Assoc::NonAssoc, rhs);
+        assert_eq!(eq(eq(atom("a"), atom("b")), atom("c")).doc.render(80), "(a == b) == c");
+        assert_eq!(eq(atom("a"), eq(atom("b"), atom("c"))).doc.render(80), "a == (b == c)");
+        // The priority just above the operator's doesn't overflow.
+        let top = |s| Parenable::new(Doc::from(s), usize::MAX);
+        assert_eq!(top("a").infix("@", usize::MAX, Assoc::Left, top("b")).doc.render(80), "a @ b");
+    }
+
+    #[test]
+    fn prec_table_looks_operators_up_by_name() {
+        let mut table = PrecTable::new();
+        table.add_infix("+", SUM, Assoc::Left).add_prefix("-", NEG).add_postfix("!", FACT);
+        let neg = table.unary_prefix("-", sum(atom("x"), atom("y"))).unwrap();
+        assert_eq!(neg.doc.render(80), "-(x + y)");
+        let fact = table.unary_postfix(neg, "!").unwrap();
+        assert_eq!(table.binary("+", atom("a"), fact).unwrap().doc.render(80), "a + (-(x + y))!");
+
+        assert!(table.binary("*", atom("a"), atom("b")).is_none());
+        // `-` is only registered as a prefix operator.
+        assert!(table.binary("-", atom("a"), atom("b")).is_none());
+        assert!(table.unary_prefix("!", atom("a")).is_none());
+        assert!(table.unary_postfix(atom("a"), "-").is_none());
+    }
+}
@@ -0,0 +1,130 @@
+use alloc::string::{ String, ToString };
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use crate::doc::{ display_width, join, AnnotatedDoc };
+
+// Names a region made with `Doc::placeholder`, so it can be found again
+// in a `PartialRender`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PlaceholderId(pub usize);
+
+// Where a placeholder's default content ended up in the output. `range`
+// is a byte range into `PartialRender::text`; `line` and `column` are
+// where it starts (both zero-based, `column` in display columns).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlaceholderSpan {
+    pub id : PlaceholderId,
+    pub range : Range<usize>,
+    pub line : usize,
+    pub column : usize,
+}
+
+// The result of `Doc::render_partial`: the rendered text, plus the
+// extent of every placeholder in it, in the order they were entered. It
+// keeps the document and the width too, for `substitute`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartialRender<A : 'static = ()> {
+    text : String,
+    spans : Vec<PlaceholderSpan>,
+    doc : AnnotatedDoc<A>,
+    width : usize,
+}
+
+impl<A : 'static> PartialRender<A> {
+    pub(crate) fn new(text : String, extents : Vec<(PlaceholderId, Range<usize>)>, doc : AnnotatedDoc<A>, width : usize) -> Self {
+        let mut partial = PartialRender {
+            text,
+            spans : extents.into_iter()
+                           .map(|(id, range)| PlaceholderSpan { id, range, line : 0, column : 0 })
+                           .collect(),
+            doc,
+            width,
+        };
+        partial.relocate();
+        partial
+    }
+
+    pub fn text(&self) -> &str {
+        self.text.as_str()
+    }
+
+    pub fn spans(&self) -> &[PlaceholderSpan] {
+        self.spans.as_slice()
+    }
+
+    pub fn into_string(self) -> String {
+        self.text
+    }
+
+    // Replace the content of every placeholder named `id` with
+    // `replacement`, and lay the document out again with that in place, so
+    // that what follows moves over and groups around it break if it
+    // doesn't fit anymore. Each newline in `replacement` is a `hardline`,
+    // so the lines after it are indented like any others.
+    // Afterwards the placeholder spans exactly the replacement, so it can
+    // be substituted again; placeholders nested inside it are gone.
+    // Returns whether any placeholder named `id` was found; if none was,
+    // nothing changes.
+    pub fn substitute(&mut self, id : PlaceholderId, replacement : &str) -> bool
+    where A : Clone + Send + Sync {
+        if !self.spans.iter().any(|span| span.id == id) {
+            return false
+        }
+        let lines = replacement.split('\n')
+                               .map(|line| AnnotatedDoc::text(line.strip_suffix('\r').unwrap_or(line).to_string()))
+                               .collect::<Vec<_>>();
+        let content = join(&lines, &AnnotatedDoc::hardline());
+        self.doc = self.doc.fill_placeholder(id, &content);
+        let PartialRender { text, spans, .. } = self.doc.render_partial(self.width);
+        self.text = text;
+        self.spans = spans;
+        true
+    }
+
+    // Recompute every span's line and column from its byte offset.
+    fn relocate(&mut self) {
+        for span in self.spans.iter_mut() {
+            let before = &self.text[..span.range.start];
+            span.line = before.matches('\n').count();
+            span.column = match before.rfind('\n') {
+                Some(nl) => display_width(&before[nl + 1..]),
+                None     => display_width(before),
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::doc::Doc;
+
+    const NAME : PlaceholderId = PlaceholderId(0);
+
+    #[test]
+    fn longer_word_shifts_the_rest_of_the_line() {
+        let doc = Doc::from("hello ").concat(Doc::placeholder(NAME, Doc::from("bob"))).concat(", hi");
+        let mut partial = doc.render_partial(80);
+        assert_eq!(partial.text(), "hello bob, hi");
+        assert!(partial.substitute(NAME, "everyone"));
+        assert_eq!(partial.text(), "hello everyone, hi");
+        assert_eq!(partial.spans()[0].range, 6..14);
+        assert!(!partial.substitute(PlaceholderId(1), "x"));
+    }
+
+    #[test]
+    fn substitute_lays_out_again() {
+        let doc = Doc::from("f(")
+                  .concat(Doc::softbreak().concat(Doc::placeholder(NAME, Doc::from("x"))).nest(2))
+                  .concat(Doc::softbreak()).concat(")").group()
+                  .concat(Doc::hardline()).concat("end");
+        let mut partial = doc.render_partial(10);
+        assert_eq!(partial.text(), "f(x)\nend");
+        partial.substitute(NAME, "a_long_argument");
+        assert_eq!(partial.text(), "f(\n  a_long_argument\n)\nend");
+        assert_eq!((partial.spans()[0].line, partial.spans()[0].column), (1, 2));
+        partial.substitute(NAME, "a\nb");
+        assert_eq!(partial.text(), "f(\n  a\n  b\n)\nend");
+    }
+}